@@ -0,0 +1,269 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::time::Duration;
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage, Frames};
+
+use crate::sprite::{AssetError, SpriteFrame};
+
+// Browsers clamp GIF delays of 10ms or less to 100ms, files made for them rely
+// on that. GIF delays count in 10ms steps, so anything below 20ms is clamped.
+const MIN_DELAY: Duration = Duration::from_millis(20);
+const BROWSER_DELAY: Duration = Duration::from_millis(100);
+
+// Frames of an animated GIF, APNG or WebP file
+pub struct AnimatedImage {
+    pub frames: Vec<SpriteFrame>,
+    // How often the file wants to be played, None loops forever
+    pub plays: Option<u32>,
+}
+
+pub fn is_animated_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            ["gif", "png", "apng", "webp"]
+                .iter()
+                .any(|known| ext.eq_ignore_ascii_case(known))
+        })
+        && path.is_file()
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, AssetError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| AssetError::Io(path.to_path_buf(), e))
+}
+
+pub fn load_animated_image<P: AsRef<Path>>(path: P) -> Result<AnimatedImage, AssetError> {
+    let path = path.as_ref();
+    let image_error = |e| AssetError::Image(path.to_path_buf(), e);
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    // The image crate decodes the frames but does not tell the loop count, that
+    // needs a second look with the format crates
+    let (frames, plays) = match extension.as_str() {
+        "gif" => {
+            let decoder = gif::DecodeOptions::new()
+                .read_info(open(path)?)
+                .map_err(|e| AssetError::Invalid(format!("{}: {}", path.display(), e)))?;
+            // Repeats after the first play, no loop extension plays once
+            let plays = match decoder.repeat() {
+                gif::Repeat::Infinite => None,
+                gif::Repeat::Finite(repeats) => Some(repeats as u32 + 1),
+            };
+            let frames = GifDecoder::new(open(path)?)
+                .map_err(image_error)?
+                .into_frames();
+            (collect_frames(frames, true).map_err(image_error)?, plays)
+        }
+        "webp" => {
+            let decoder = image_webp::WebPDecoder::new(open(path)?)
+                .map_err(|e| AssetError::Invalid(format!("{}: {}", path.display(), e)))?;
+            let plays = match decoder.loop_count() {
+                image_webp::LoopCount::Forever => None,
+                image_webp::LoopCount::Times(plays) => Some(plays.get() as u32),
+            };
+            let decoder = WebPDecoder::new(open(path)?).map_err(image_error)?;
+            if !decoder.has_animation() {
+                return still_image(path);
+            }
+            (
+                collect_frames(decoder.into_frames(), false).map_err(image_error)?,
+                plays,
+            )
+        }
+        _ => {
+            let reader = png::Decoder::new(open(path)?)
+                .read_info()
+                .map_err(|e| AssetError::Invalid(format!("{}: {}", path.display(), e)))?;
+            let Some(control) = reader.info().animation_control else {
+                return still_image(path);
+            };
+            // Zero plays loops forever
+            let plays = Some(control.num_plays).filter(|plays| *plays > 0);
+            let decoder = PngDecoder::new(open(path)?).map_err(image_error)?;
+            let frames = decoder.apng().map_err(image_error)?.into_frames();
+            (collect_frames(frames, false).map_err(image_error)?, plays)
+        }
+    };
+
+    if frames.is_empty() {
+        return Err(AssetError::Invalid(format!(
+            "{} has no frames",
+            path.display()
+        )));
+    }
+    println!("Loaded {} frames from {}", frames.len(), path.display());
+    Ok(AnimatedImage { frames, plays })
+}
+
+fn collect_frames(frames: Frames, browser_delays: bool) -> image::ImageResult<Vec<SpriteFrame>> {
+    frames
+        .enumerate()
+        .map(|(index, frame)| {
+            let frame = frame?;
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let mut delay = Duration::from_millis((numer / denom.max(1)) as u64);
+            if browser_delays && delay < MIN_DELAY {
+                delay = BROWSER_DELAY;
+            }
+            Ok(SpriteFrame {
+                name: index.to_string(),
+                image: DynamicImage::ImageRgba8(frame.into_buffer()),
+                duration: Some(delay),
+            })
+        })
+        .collect()
+}
+
+fn still_image(path: &Path) -> Result<AnimatedImage, AssetError> {
+    let image = image::open(path).map_err(|e| AssetError::Image(path.to_path_buf(), e))?;
+    Ok(AnimatedImage {
+        frames: vec![SpriteFrame {
+            name: "0".to_string(),
+            image,
+            duration: None,
+        }],
+        plays: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use image::codecs::gif::{GifEncoder, Repeat};
+    use image::{Delay, Frame, Rgba, RgbaImage};
+
+    #[test]
+    fn test_load_gif() {
+        let dir = TempDir::new("gif");
+        let path = dir.join("blink.gif");
+        {
+            let mut encoder = GifEncoder::new(File::create(&path).unwrap());
+            encoder.set_repeat(Repeat::Finite(2)).unwrap();
+            for (color, delay) in [([0xFF, 0, 0, 0xFF], 250), ([0, 0xFF, 0, 0xFF], 0)] {
+                let frame = Frame::from_parts(
+                    RgbaImage::from_pixel(2, 2, Rgba(color)),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(delay, 1),
+                );
+                encoder.encode_frame(frame).unwrap();
+            }
+        }
+
+        let image = load_animated_image(&path).unwrap();
+        assert_eq!(image.plays, Some(3));
+        let durations: Vec<_> = image
+            .frames
+            .iter()
+            .map(|frame| frame.duration.unwrap())
+            .collect();
+        assert_eq!(durations, vec![Duration::from_millis(250), BROWSER_DELAY]);
+        assert_eq!(
+            image.frames[1].image.to_rgba8()[(1, 1)],
+            Rgba([0, 0xFF, 0, 0xFF])
+        );
+
+        // A plain PNG is a single frame without a duration
+        RgbaImage::new(2, 2).save(dir.join("still.png")).unwrap();
+        let still = load_animated_image(dir.join("still.png")).unwrap();
+        assert_eq!(still.frames.len(), 1);
+        assert!(still.frames[0].duration.is_none());
+    }
+
+    const RED: [u8; 4] = [0xFF, 0, 0, 0xFF];
+    const GREEN: [u8; 4] = [0, 0xFF, 0, 0xFF];
+
+    #[test]
+    fn test_load_apng() {
+        let dir = TempDir::new("apng");
+        let path = dir.join("blink.png");
+        {
+            let mut encoder = png::Encoder::new(File::create(&path).unwrap(), 2, 2);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_animated(2, 3).unwrap();
+            let mut writer = encoder.write_header().unwrap();
+            for (color, delay) in [(RED, 250), (GREEN, 0)] {
+                writer.set_frame_delay(delay, 1000).unwrap();
+                writer.write_image_data(&color.repeat(4)).unwrap();
+            }
+        }
+
+        let image = load_animated_image(&path).unwrap();
+        assert_eq!(image.plays, Some(3));
+        // Only GIFs get the delays of browsers
+        let durations: Vec<_> = image
+            .frames
+            .iter()
+            .map(|frame| frame.duration.unwrap())
+            .collect();
+        assert_eq!(durations, vec![Duration::from_millis(250), Duration::ZERO]);
+        assert_eq!(image.frames[1].image.to_rgba8()[(1, 1)], Rgba(GREEN));
+    }
+
+    fn chunk(fourcc: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = [fourcc, &(data.len() as u32).to_le_bytes(), data].concat();
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn u24(value: u32) -> [u8; 3] {
+        let [a, b, c, _] = value.to_le_bytes();
+        [a, b, c]
+    }
+
+    #[test]
+    fn test_load_webp() {
+        // image-webp only encodes still images, their VP8L chunks become the frames
+        // of an animated file
+        let frame = |color: [u8; 4], duration: u32| {
+            let mut still = Vec::new();
+            image_webp::WebPEncoder::new(&mut still)
+                .encode(&color.repeat(4), 2, 2, image_webp::ColorType::Rgba8)
+                .unwrap();
+            // Offset, size minus one, duration and "do not blend"
+            let header = [u24(0), u24(0), u24(1), u24(1), u24(duration)].concat();
+            chunk(b"ANMF", &[&header[..], &[0x02], &still[12..]].concat())
+        };
+        let vp8x = [&[0x12, 0, 0, 0][..], &u24(1), &u24(1)].concat();
+        // Background color and loop count
+        let anim = [0, 0, 0, 0, 2, 0];
+        let body = [
+            &b"WEBP"[..],
+            &chunk(b"VP8X", &vp8x),
+            &chunk(b"ANIM", &anim),
+            &frame(RED, 250),
+            &frame(GREEN, 40),
+        ]
+        .concat();
+        let dir = TempDir::new("webp");
+        let path = dir.join("blink.webp");
+        std::fs::write(&path, chunk(b"RIFF", &body)).unwrap();
+
+        let image = load_animated_image(&path).unwrap();
+        assert_eq!(image.plays, Some(2));
+        let durations: Vec<_> = image
+            .frames
+            .iter()
+            .map(|frame| frame.duration.unwrap())
+            .collect();
+        assert_eq!(
+            durations,
+            vec![Duration::from_millis(250), Duration::from_millis(40)]
+        );
+        assert_eq!(image.frames[1].image.to_rgba8()[(1, 1)], Rgba(GREEN));
+    }
+}
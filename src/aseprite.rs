@@ -0,0 +1,255 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use image::{imageops, DynamicImage, RgbaImage};
+use serde::Deserialize;
+
+use crate::sprite::{natural_cmp, Animation, AssetError, LoopMode, SpriteFrame};
+
+// Sprite sheets exported by Aseprite through File > Export Sprite Sheet with
+// "JSON Data" enabled. Both the array and the hash layout of the frame list are
+// supported, tags become animations.
+#[derive(Debug, Deserialize)]
+struct SheetFile {
+    frames: SheetFrames,
+    meta: SheetMeta,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SheetFrames {
+    Array(Vec<SheetFrame>),
+    // Keyed by file name. Aseprite numbers them "name 0.aseprite",
+    // "name 1.aseprite", ..., so the natural order is the frame order.
+    Hash(BTreeMap<String, SheetFrame>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SheetFrame {
+    #[serde(default)]
+    filename: String,
+    frame: Rect,
+    #[serde(default)]
+    rotated: bool,
+    // Position of the trimmed frame within the original canvas
+    sprite_source_size: Option<Rect>,
+    source_size: Option<Size>,
+    duration: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct Rect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct Size {
+    w: u32,
+    h: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SheetMeta {
+    image: PathBuf,
+    #[serde(default)]
+    frame_tags: Vec<SheetTag>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SheetTag {
+    name: String,
+    from: usize,
+    to: usize,
+    #[serde(default)]
+    direction: Direction,
+    // Play count as a string, missing or empty loops forever
+    #[serde(default)]
+    repeat: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Direction {
+    #[default]
+    Forward,
+    Reverse,
+    Pingpong,
+    PingpongReverse,
+}
+
+pub struct SpriteSheet {
+    pub frames: Vec<SpriteFrame>,
+    pub animations: Vec<Animation>,
+}
+
+pub fn is_sheet(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+// Loads the JSON file of a sheet and slices the image it refers to
+pub fn load_sheet<P: AsRef<Path>>(path: P) -> Result<SpriteSheet, AssetError> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path).map_err(|e| AssetError::Io(path.to_path_buf(), e))?;
+    let sheet: SheetFile =
+        serde_json::from_str(&content).map_err(|e| AssetError::Sheet(path.to_path_buf(), e))?;
+    let image_path = path.parent().unwrap_or(Path::new(".")).join(&sheet.meta.image);
+    let image = image::open(&image_path).map_err(|e| AssetError::Image(image_path.clone(), e))?;
+    let invalid = |reason: String| AssetError::Invalid(format!("{}: {}", path.display(), reason));
+
+    let frames = match sheet.frames {
+        SheetFrames::Array(frames) => frames,
+        SheetFrames::Hash(frames) => {
+            let mut frames: Vec<_> = frames
+                .into_iter()
+                .map(|(filename, frame)| SheetFrame { filename, ..frame })
+                .collect();
+            frames.sort_by(|a, b| natural_cmp(&a.filename, &b.filename));
+            frames
+        }
+    };
+    if frames.is_empty() {
+        return Err(invalid("has no frames".to_string()));
+    }
+
+    let frames = frames
+        .into_iter()
+        .map(|frame| {
+            let Rect { x, y, w, h } = frame.frame;
+            if frame.rotated {
+                return Err(invalid(format!("frame '{}' is rotated", frame.filename)));
+            }
+            let outside = |start: u32, size: u32, limit| start.checked_add(size).is_none_or(|end| end > limit);
+            if outside(x, w, image.width()) || outside(y, h, image.height()) {
+                return Err(invalid(format!("frame '{}' is outside of the sheet", frame.filename)));
+            }
+            let mut cropped = image.crop_imm(x, y, w, h);
+            // Trimmed frames go back onto a transparent canvas of the original size,
+            // otherwise the sprite jumps around
+            if let (Some(source), Some(size)) = (frame.sprite_source_size, frame.source_size) {
+                if source.x != 0 || source.y != 0 || size.w != w || size.h != h {
+                    let mut canvas = RgbaImage::new(size.w, size.h);
+                    imageops::overlay(&mut canvas, &cropped, source.x as i64, source.y as i64);
+                    cropped = DynamicImage::ImageRgba8(canvas);
+                }
+            }
+            Ok(SpriteFrame {
+                name: frame.filename,
+                image: cropped,
+                duration: frame.duration.map(Duration::from_millis),
+            })
+        })
+        .collect::<Result<Vec<_>, AssetError>>()?;
+
+    let animations = sheet
+        .meta
+        .frame_tags
+        .into_iter()
+        .map(|tag| {
+            if tag.from > tag.to || tag.to >= frames.len() {
+                return Err(invalid(format!("tag '{}' is outside of the frames", tag.name)));
+            }
+            let mut sequence: Vec<usize> = (tag.from..=tag.to).collect();
+            if matches!(tag.direction, Direction::Reverse | Direction::PingpongReverse) {
+                sequence.reverse();
+            }
+            let loop_mode = match tag.direction {
+                Direction::Forward | Direction::Reverse => LoopMode::Loop,
+                Direction::Pingpong | Direction::PingpongReverse => LoopMode::PingPong,
+            };
+            let plays = match tag.repeat.as_str() {
+                "" | "0" => None,
+                repeat => Some(repeat.parse().map_err(|_| {
+                    invalid(format!("tag '{}' has an invalid repeat '{}'", tag.name, repeat))
+                })?),
+            };
+            Ok(Animation {
+                name: tag.name,
+                frames: sequence,
+                loop_mode,
+                plays,
+            })
+        })
+        .collect::<Result<Vec<_>, AssetError>>()?;
+
+    println!(
+        "Loaded {} frames and {} animations from {}",
+        frames.len(),
+        animations.len(),
+        path.display()
+    );
+    Ok(SpriteSheet { frames, animations })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_load_sheet() {
+        let dir = TempDir::new("sheet");
+        // Three 2x2 frames side by side, the last one trimmed to its right column
+        let mut image = RgbaImage::new(5, 2);
+        for (x, color) in [(0, [0xFF, 0, 0, 0xFF]), (2, [0, 0xFF, 0, 0xFF]), (4, [0, 0, 0xFF, 0xFF])] {
+            for y in 0..2 {
+                image.put_pixel(x, y, image::Rgba(color));
+            }
+        }
+        image.save(dir.join("sheet.png")).unwrap();
+        fs::write(
+            dir.join("sheet.json"),
+            r#"{
+                "frames": {
+                    "walk 10.aseprite": { "frame": { "x": 4, "y": 0, "w": 1, "h": 2 }, "rotated": false,
+                        "spriteSourceSize": { "x": 1, "y": 0, "w": 1, "h": 2 }, "sourceSize": { "w": 2, "h": 2 },
+                        "duration": 300 },
+                    "walk 2.aseprite": { "frame": { "x": 2, "y": 0, "w": 2, "h": 2 }, "duration": 200 },
+                    "walk 1.aseprite": { "frame": { "x": 0, "y": 0, "w": 2, "h": 2 }, "duration": 100 }
+                },
+                "meta": {
+                    "app": "https://www.aseprite.org/",
+                    "image": "sheet.png",
+                    "frameTags": [
+                        { "name": "idle", "from": 0, "to": 0, "direction": "forward" },
+                        { "name": "walk", "from": 1, "to": 2, "direction": "pingpong_reverse", "repeat": "2" }
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let sheet = load_sheet(dir.join("sheet.json")).unwrap();
+        let durations: Vec<_> = sheet.frames.iter().map(|frame| frame.duration.unwrap().as_millis()).collect();
+        assert_eq!(durations, vec![100, 200, 300]);
+        let trimmed = sheet.frames[2].image.to_rgba8();
+        assert_eq!(trimmed.dimensions(), (2, 2));
+        assert_eq!(trimmed[(0, 0)], image::Rgba([0, 0, 0, 0]));
+        assert_eq!(trimmed[(1, 1)], image::Rgba([0, 0, 0xFF, 0xFF]));
+
+        let walk = &sheet.animations[1];
+        assert_eq!(walk.name, "walk");
+        assert_eq!(walk.frames, vec![2, 1]);
+        assert!(matches!(walk.loop_mode, LoopMode::PingPong));
+        assert_eq!(walk.plays, Some(2));
+        assert_eq!(sheet.animations[0].plays, None);
+
+        fs::write(
+            dir.join("sheet.json"),
+            r#"{ "frames": [{ "frame": { "x": 4294967295, "y": 0, "w": 2, "h": 2 } }], "meta": { "image": "sheet.png" } }"#,
+        )
+        .unwrap();
+        assert!(matches!(load_sheet(dir.join("sheet.json")), Err(AssetError::Invalid(_))));
+
+        fs::write(dir.join("sheet.json"), r#"{ "frames": [], "meta": { "image": "sheet.png" } }"#).unwrap();
+        assert!(matches!(load_sheet(dir.join("sheet.json")), Err(AssetError::Invalid(_))));
+    }
+}
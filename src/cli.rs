@@ -0,0 +1,261 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use pnet::util::MacAddr;
+
+use crate::linsn::{ColorFormat, FrameGeometry};
+use crate::scheduler::{OutputConfig, DEFAULT_REFRESH_RATE};
+use crate::test_pattern::TestPattern;
+
+// Every option can also be given through the LINSN_* environment variable
+// named in --help, so existing setups keep working
+#[derive(Debug, Parser)]
+#[command(version, about = "Drives Linsn LED sender cards over raw Ethernet")]
+pub struct Cli {
+    /// Network interface frames are sent on and received from
+    #[arg(short, long, global = true, env = "LINSN_INTERFACE")]
+    pub interface: Option<String>,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Plays a scene file on the wall
+    Run {
+        #[arg(default_value = "scenes/demo.toml")]
+        scene: PathBuf,
+    },
+    /// Renders the first frame of a scene through the encoder and a virtual
+    /// receiver card into an image, no interface needed
+    Preview {
+        #[arg(default_value = "scenes/demo.toml")]
+        scene: PathBuf,
+        #[arg(long, default_value = "preview.png")]
+        image: PathBuf,
+    },
+    /// Shows a static pattern to check wiring, color order and brightness
+    TestPattern {
+        #[arg(value_enum, default_value_t = TestPattern::Bars)]
+        pattern: TestPattern,
+    },
+    /// Plays a video file
+    #[cfg(feature = "screen-capture")]
+    Play { video: PathBuf },
+    /// Mirrors the X11 screen
+    #[cfg(feature = "screen-capture")]
+    CaptureScreen,
+    /// Lists the network interfaces frames can be sent on
+    ListInterfaces,
+    /// Lists the receiver cards answering on the interface
+    Listen,
+    /// Decodes frames seen on the interface, or stored in the --pcap file
+    Receive {
+        /// Saves every decoded frame to this image
+        snapshot: Option<PathBuf>,
+    },
+    /// Sends a recorded capture file again
+    Replay {
+        capture: PathBuf,
+        /// Playback speed, 2.0 sends twice as fast as recorded
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+    /// Sends a single CONFIG (0x61) command to the receiver cards. The meaning of
+    /// the settings is not documented, repeat what the vendor software sends.
+    Config {
+        /// The first 16 command bytes as hex, e.g. copied from a capture. The
+        /// sender MAC after them is taken from the interface.
+        #[arg(value_parser = parse_config_settings)]
+        settings: [u8; 16],
+        #[arg(long, default_value_t = 0)]
+        package_id: u32,
+    },
+    /// Measures the frame rate of every sender backend
+    Bench {
+        #[arg(long, default_value_t = 600)]
+        frames: u32,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct OutputArgs {
+    /// Writes sent frames into this capture file instead of the interface,
+    /// receive reads them from it
+    #[arg(long, global = true)]
+    pub pcap: Option<PathBuf>,
+
+    /// Socket implementation used for sending
+    #[arg(long, global = true, value_enum, default_value_t = SenderBackend::Batched, env = "LINSN_SENDER")]
+    pub backend: SenderBackend,
+
+    /// Destination MAC of the Linsn frames
+    #[arg(long, global = true, default_value_t = MacAddr::zero())]
+    pub dst_mac: MacAddr,
+
+    /// Byte order in which the LED modules expect the color channels
+    #[arg(long, global = true, default_value = "BRG", env = "LINSN_COLOR_FORMAT")]
+    pub color_order: ColorFormat,
+
+    /// Initial brightness in percent, every number on stdin changes it later
+    #[arg(long, global = true, default_value_t = 100, env = "LINSN_BRIGHTNESS",
+        value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub brightness: u8,
+
+    /// Size of the canvas scenes draw on, ignored with --mapping
+    #[arg(long, global = true, default_value = "192x192")]
+    pub panel_size: PanelSize,
+
+    /// Canvas of the sender card as WIDTHxHEIGHT[+ROW_OFFSET], defaults to 1024x512+1
+    #[arg(long, global = true, env = "LINSN_GEOMETRY")]
+    pub geometry: Option<FrameGeometry>,
+
+    /// Cabinet layout of walls made of several cabinets
+    #[arg(long, global = true, env = "LINSN_MAPPING")]
+    pub mapping: Option<PathBuf>,
+
+    /// Gamma and gain calibration of the LED modules
+    #[arg(long, global = true, env = "LINSN_CALIBRATION")]
+    pub calibration: Option<PathBuf>,
+
+    /// Frames per second sent to the wall
+    #[arg(long, global = true, default_value_t = DEFAULT_REFRESH_RATE, env = "LINSN_REFRESH_RATE",
+        value_parser = parse_refresh_rate)]
+    pub refresh_rate: f64,
+
+    /// Skips unchanged frames, but resends at least every so many milliseconds.
+    /// Receivers have to keep showing the last frame.
+    #[arg(long, global = true, env = "LINSN_KEEPALIVE_MS")]
+    pub keepalive_ms: Option<u64>,
+}
+
+impl OutputArgs {
+    pub fn geometry(&self) -> FrameGeometry {
+        self.geometry.unwrap_or_default()
+    }
+
+    pub fn output_config(&self) -> OutputConfig {
+        OutputConfig {
+            refresh_rate: self.refresh_rate,
+            skip_unchanged: self.keepalive_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum SenderBackend {
+    // One send call per packet through pnet
+    Simple,
+    // All packets of a frame in a single sendmmsg call
+    Batched,
+    // PACKET_MMAP TX ring
+    Ring,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PanelSize {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl FromStr for PanelSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid panel size '{}', expected WIDTHxHEIGHT", s);
+        let (width, height) = s.split_once('x').ok_or_else(invalid)?;
+        let size = PanelSize {
+            width: width.parse().map_err(|_| invalid())?,
+            height: height.parse().map_err(|_| invalid())?,
+        };
+        if size.width == 0 || size.height == 0 {
+            return Err(invalid());
+        }
+        Ok(size)
+    }
+}
+
+fn parse_refresh_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("'{}' is not a positive number", s)),
+    }
+}
+
+// Hex digits, optionally separated by spaces or colons like in Wireshark
+fn parse_config_settings(s: &str) -> Result<[u8; 16], String> {
+    let digits: Vec<char> = s.chars().filter(|c| !c.is_whitespace() && *c != ':').collect();
+    if digits.len() != 32 {
+        return Err(format!("expected 16 bytes of hex, got {} digits", digits.len()));
+    }
+    let mut settings = [0u8; 16];
+    for (byte, pair) in settings.iter_mut().zip(digits.chunks(2)) {
+        let pair: String = pair.iter().collect();
+        *byte = u8::from_str_radix(&pair, 16).map_err(|_| format!("'{}' is not a hex byte", pair))?;
+    }
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_options_after_subcommand() {
+        let cli = Cli::try_parse_from([
+            "sender",
+            "test-pattern",
+            "grid",
+            "-i",
+            "eth1",
+            "--backend",
+            "ring",
+            "--dst-mac",
+            "02:00:00:00:00:01",
+            "--color-order",
+            "rgb",
+            "--brightness",
+            "40",
+            "--panel-size",
+            "384x192",
+            "--pcap",
+            "out.pcap",
+        ])
+        .unwrap();
+
+        assert!(matches!(cli.command, Command::TestPattern { pattern: TestPattern::Grid }));
+        assert_eq!(cli.interface.as_deref(), Some("eth1"));
+        assert_eq!(cli.output.backend, SenderBackend::Ring);
+        assert_eq!(cli.output.dst_mac, MacAddr::new(2, 0, 0, 0, 0, 1));
+        assert_eq!(cli.output.color_order, ColorFormat::RGB);
+        assert_eq!(cli.output.brightness, 40);
+        assert_eq!(cli.output.panel_size, PanelSize { width: 384, height: 192 });
+        assert_eq!(cli.output.pcap.as_deref(), Some(std::path::Path::new("out.pcap")));
+
+        assert!(Cli::try_parse_from(["sender", "run", "--brightness", "101"]).is_err());
+        assert!(Cli::try_parse_from(["sender", "run", "--refresh-rate", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_config_settings() {
+        let mut expected = [0u8; 16];
+        expected[0] = 0xA5;
+        expected[15] = 0x0F;
+        let hex = format!("a5:{}0f", "00 ".repeat(14));
+        assert_eq!(parse_config_settings(&hex), Ok(expected));
+        assert!(parse_config_settings("a5").is_err());
+        assert!(parse_config_settings(&"zz".repeat(16)).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+// Global brightness of the wall. Clones share the same level, so a handle kept
+// by another thread can dim a sender while it is running.
+#[derive(Debug, Clone)]
+pub struct Brightness {
+    level: Arc<AtomicU8>,
+}
+
+impl Brightness {
+    pub fn new(level: u8) -> Self {
+        Brightness {
+            level: Arc::new(AtomicU8::new(level)),
+        }
+    }
+
+    pub fn from_percent(percent: u8) -> Self {
+        let brightness = Brightness::default();
+        brightness.set_percent(percent);
+        brightness
+    }
+
+    pub fn get(&self) -> u8 {
+        self.level.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, level: u8) {
+        self.level.store(level, Ordering::Relaxed);
+    }
+
+    pub fn set_percent(&self, percent: u8) {
+        let percent = percent.min(100) as u32;
+        self.set(((percent * 255 + 50) / 100) as u8);
+    }
+
+    // Maps every color byte to its dimmed value, cheap enough to rebuild per frame
+    pub fn lut(&self) -> [u8; 256] {
+        let level = self.get() as u32;
+        let mut lut = [0u8; 256];
+        for (value, entry) in lut.iter_mut().enumerate() {
+            *entry = ((value as u32 * level + 127) / 255) as u8;
+        }
+        lut
+    }
+}
+
+impl Default for Brightness {
+    fn default() -> Self {
+        Brightness::new(0xFF)
+    }
+}
+
+#[derive(Debug)]
+pub enum CalibrationError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrationError::Io(e) => write!(f, "failed to read calibration: {}", e),
+            CalibrationError::Parse(e) => write!(f, "failed to parse calibration: {}", e),
+            CalibrationError::Invalid(reason) => write!(f, "invalid calibration: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CalibrationError {}
+
+// Either one value for all channels or one value per channel in RGB order
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+enum PerChannel {
+    All(f32),
+    Each([f32; 3]),
+}
+
+impl PerChannel {
+    fn values(self) -> [f32; 3] {
+        match self {
+            PerChannel::All(value) => [value; 3],
+            PerChannel::Each(values) => values,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LutFile {
+    red: Vec<u8>,
+    green: Vec<u8>,
+    blue: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CalibrationFile {
+    gamma: Option<PerChannel>,
+    gain: Option<PerChannel>,
+    lut: Option<LutFile>,
+}
+
+// Per-channel correction applied to the composed image before packetization.
+// Each channel runs through its own 256-entry table, built either from a gamma
+// curve or given verbatim, followed by a white-balance gain.
+//
+// Calibration files are TOML:
+//
+//     gamma = 2.2              # or [2.2, 2.4, 2.2]
+//     gain = [1.0, 0.92, 0.85]
+//
+//     [lut]                    # optional, replaces gamma
+//     red = [0, 0, 1, ...]     # 256 entries per channel
+//     green = [...]
+//     blue = [...]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Calibration {
+    luts: [[u8; 256]; 3],
+}
+
+impl Calibration {
+    pub fn identity() -> Self {
+        Calibration::from_gamma([1.0; 3], [1.0; 3])
+    }
+
+    pub fn from_gamma(gamma: [f32; 3], gain: [f32; 3]) -> Self {
+        let mut luts = [[0u8; 256]; 3];
+        for (channel, lut) in luts.iter_mut().enumerate() {
+            for (value, entry) in lut.iter_mut().enumerate() {
+                let linear = (value as f32 / 255.0).powf(gamma[channel]);
+                *entry = (linear * gain[channel] * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        Calibration { luts }
+    }
+
+    pub fn from_luts(luts: [[u8; 256]; 3], gain: [f32; 3]) -> Self {
+        let mut luts = luts;
+        for (channel, lut) in luts.iter_mut().enumerate() {
+            for entry in lut.iter_mut() {
+                *entry = (*entry as f32 * gain[channel]).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        Calibration { luts }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CalibrationError> {
+        let content = fs::read_to_string(path).map_err(CalibrationError::Io)?;
+        Calibration::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, CalibrationError> {
+        let file: CalibrationFile = toml::from_str(content).map_err(CalibrationError::Parse)?;
+        let gain = file.gain.map(PerChannel::values).unwrap_or([1.0; 3]);
+        // A gain of 0 switches a channel off, e.g. one with broken LEDs
+        if gain.iter().any(|g| !g.is_finite() || *g < 0.0) {
+            return Err(CalibrationError::Invalid(
+                "gains must not be negative".to_string(),
+            ));
+        }
+
+        match (file.lut, file.gamma) {
+            (Some(_), Some(_)) => Err(CalibrationError::Invalid(
+                "use either gamma or lut, not both".to_string(),
+            )),
+            (Some(lut), None) => {
+                let mut luts = [[0u8; 256]; 3];
+                for (table, (name, values)) in luts.iter_mut().zip([
+                    ("red", lut.red),
+                    ("green", lut.green),
+                    ("blue", lut.blue),
+                ]) {
+                    *table = values.try_into().map_err(|values: Vec<u8>| {
+                        CalibrationError::Invalid(format!(
+                            "{} table has {} entries instead of 256",
+                            name,
+                            values.len()
+                        ))
+                    })?;
+                }
+                Ok(Calibration::from_luts(luts, gain))
+            }
+            (None, gamma) => {
+                let gamma = gamma.map(PerChannel::values).unwrap_or([1.0; 3]);
+                if gamma.iter().any(|g| !g.is_finite() || *g <= 0.0) {
+                    return Err(CalibrationError::Invalid(
+                        "gamma must be positive".to_string(),
+                    ));
+                }
+                Ok(Calibration::from_gamma(gamma, gain))
+            }
+        }
+    }
+
+    // Folds the brightness into the calibration so the send path needs a single
+    // lookup per color byte
+    pub fn channel_luts(&self, brightness: &Brightness) -> [[u8; 256]; 3] {
+        let dim = brightness.lut();
+        let mut luts = self.luts;
+        for lut in luts.iter_mut() {
+            for entry in lut.iter_mut() {
+                *entry = dim[*entry as usize];
+            }
+        }
+        luts
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration::identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    fn apply(calibration: &Calibration, pixel: Rgb<u8>) -> Rgb<u8> {
+        Rgb([
+            calibration.luts[0][pixel[0] as usize],
+            calibration.luts[1][pixel[1] as usize],
+            calibration.luts[2][pixel[2] as usize],
+        ])
+    }
+
+    #[test]
+    fn test_lut() {
+        let brightness = Brightness::default();
+        let lut = brightness.lut();
+        assert!(lut.iter().enumerate().all(|(i, v)| i == *v as usize));
+
+        brightness.set_percent(50);
+        let lut = brightness.lut();
+        assert_eq!(lut[0], 0);
+        assert_eq!(lut[255], 128);
+
+        brightness.set(0);
+        assert!(brightness.lut().iter().all(|v| *v == 0));
+    }
+
+    #[test]
+    fn test_clones_share_level() {
+        let brightness = Brightness::default();
+        let handle = brightness.clone();
+        std::thread::spawn(move || handle.set_percent(20))
+            .join()
+            .unwrap();
+        assert_eq!(brightness.get(), 51);
+    }
+
+    #[test]
+    fn test_identity_calibration() {
+        let calibration = Calibration::identity();
+        let pixel = Rgb([0x12, 0x80, 0xFE]);
+        assert_eq!(apply(&calibration, pixel), pixel);
+    }
+
+    #[test]
+    fn test_parse_gamma_and_gain() {
+        let calibration = Calibration::parse("gamma = 2.2\ngain = [1.0, 0.5, 0.0]").unwrap();
+        assert_eq!(apply(&calibration, Rgb([255, 255, 255])), Rgb([255, 128, 0]));
+        assert_eq!(apply(&calibration, Rgb([0, 0, 0])), Rgb([0, 0, 0]));
+        // 50% input ends up at roughly 22% output with a gamma of 2.2
+        assert_eq!(apply(&calibration, Rgb([128, 0, 0]))[0], 56);
+        assert!(Calibration::parse("gain = [1.0, -0.5, 1.0]").is_err());
+    }
+
+    #[test]
+    fn test_parse_lut() {
+        let table = (0..=255).rev().map(|v| v.to_string()).collect::<Vec<_>>();
+        let content = format!(
+            "[lut]\nred = [{0}]\ngreen = [{0}]\nblue = [{0}]",
+            table.join(", ")
+        );
+        let calibration = Calibration::parse(&content).unwrap();
+        assert_eq!(apply(&calibration, Rgb([0, 1, 255])), Rgb([255, 254, 0]));
+
+        assert!(Calibration::parse("[lut]\nred = [1]\ngreen = [1]\nblue = [1]").is_err());
+        assert!(Calibration::parse("gamma = -1.0").is_err());
+    }
+
+    #[test]
+    fn test_channel_luts_include_brightness() {
+        let calibration = Calibration::from_gamma([1.0; 3], [1.0, 1.0, 0.5]);
+        let luts = calibration.channel_luts(&Brightness::from_percent(50));
+        assert_eq!(luts[0][255], 128);
+        assert_eq!(luts[2][255], 64);
+    }
+}
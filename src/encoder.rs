@@ -0,0 +1,297 @@
+use image::Rgb;
+use pnet::util::MacAddr;
+
+use crate::linsn::{
+    pixel_to_bytes, ColorFormat, FrameGeometry, LinsnHeader, BYTES_PER_PIXEL, CHUNK_SIZE,
+    ETHERNET_TYPE_SENDER, HEADER_SIZE, PAYLOAD_SIZE_SENDER,
+};
+
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+// Ethernet and Linsn header in front of every payload
+pub const PACKET_HEADER_SIZE: usize = ETHERNET_HEADER_SIZE + HEADER_SIZE;
+pub const PACKET_SIZE: usize = PACKET_HEADER_SIZE + PAYLOAD_SIZE_SENDER;
+
+// Chunks of the sender-card canvas that changed since the previous frame
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Damage {
+    full: bool,
+    chunks: Vec<bool>,
+}
+
+impl Damage {
+    // Everything has to be encoded again, e.g. for the very first frame
+    pub fn full() -> Self {
+        Damage {
+            full: true,
+            chunks: vec![],
+        }
+    }
+
+    // Marks the chunk holding a pixel of the sender-card canvas
+    pub fn mark_pixel(&mut self, index: usize) {
+        self.mark_chunk(index / CHUNK_SIZE);
+    }
+
+    pub fn mark_chunk(&mut self, chunk: usize) {
+        if chunk >= self.chunks.len() {
+            self.chunks.resize(chunk + 1, false);
+        }
+        self.chunks[chunk] = true;
+    }
+
+    pub fn is_dirty(&self, chunk: usize) -> bool {
+        self.full || self.chunks.get(chunk).copied().unwrap_or(false)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.full && !self.chunks.contains(&true)
+    }
+
+    pub fn merge(&mut self, other: &Damage) {
+        self.full |= other.full;
+        for (chunk, dirty) in other.chunks.iter().enumerate() {
+            if *dirty {
+                self.mark_chunk(chunk);
+            }
+        }
+    }
+
+    // Returns the damage collected so far and starts over
+    pub fn take(&mut self) -> Damage {
+        std::mem::take(self)
+    }
+}
+
+// Keeps one complete Ethernet frame per chunk of the canvas. The headers do not
+// change from frame to frame, so they are only written when the configuration
+// changes and encoding a frame just overwrites the payloads in place.
+#[derive(Debug, Clone)]
+pub struct FrameEncoder {
+    geometry: FrameGeometry,
+    src_mac: MacAddr,
+    dst_mac: MacAddr,
+    color_format: ColorFormat,
+    packets: Vec<u8>,
+    // Tables the cached payloads were encoded with, None while they are invalid
+    luts: Option<[[u8; 256]; 3]>,
+}
+
+impl FrameEncoder {
+    pub fn new(
+        geometry: FrameGeometry,
+        src_mac: MacAddr,
+        dst_mac: MacAddr,
+        color_format: ColorFormat,
+    ) -> Self {
+        let mut encoder = FrameEncoder {
+            geometry,
+            src_mac,
+            dst_mac,
+            color_format,
+            packets: vec![0u8; geometry.chunk_count() * PACKET_SIZE],
+            luts: None,
+        };
+        encoder.write_headers();
+        encoder
+    }
+
+    // Cheap when nothing changed, so senders can call it before every frame
+    pub fn configure(
+        &mut self,
+        geometry: FrameGeometry,
+        src_mac: MacAddr,
+        dst_mac: MacAddr,
+        color_format: ColorFormat,
+    ) {
+        if color_format != self.color_format {
+            self.color_format = color_format;
+            self.luts = None;
+        }
+        if geometry != self.geometry {
+            *self = FrameEncoder::new(geometry, src_mac, dst_mac, color_format);
+        } else if src_mac != self.src_mac || dst_mac != self.dst_mac {
+            self.src_mac = src_mac;
+            self.dst_mac = dst_mac;
+            self.write_headers();
+        }
+    }
+
+    fn write_headers(&mut self) {
+        for (package_id, packet) in self.packets.chunks_exact_mut(PACKET_SIZE).enumerate() {
+            packet[0..6].copy_from_slice(&self.dst_mac.octets());
+            packet[6..12].copy_from_slice(&self.src_mac.octets());
+            packet[12..14].copy_from_slice(&ETHERNET_TYPE_SENDER.to_be_bytes());
+            let header = match package_id {
+                0 => LinsnHeader::chunk_start(self.src_mac),
+                _ => LinsnHeader::empty(package_id as u32),
+            };
+            packet[ETHERNET_HEADER_SIZE..PACKET_HEADER_SIZE].copy_from_slice(&header.to_bytes());
+        }
+    }
+
+    // Writes the payloads of damaged chunks, the others still hold the bytes of
+    // the previous frame. Every channel runs through its calibration table
+    // (gamma, gain and brightness) before reordering. Changed tables or color
+    // order invalidate everything. Returns the number of chunks encoded.
+    pub fn encode_damaged(
+        &mut self,
+        image: &[Rgb<u8>],
+        luts: &[[u8; 256]; 3],
+        damage: &Damage,
+    ) -> usize {
+        debug_assert_eq!(image.len(), self.geometry.pixel_count());
+        let full = self.luts.as_ref() != Some(luts);
+        let packets = self.packets.chunks_exact_mut(PACKET_SIZE);
+        let mut encoded = 0;
+        for (index, (chunk, packet)) in image.chunks(CHUNK_SIZE).zip(packets).enumerate() {
+            if !full && !damage.is_dirty(index) {
+                continue;
+            }
+            encode_payload(
+                self.color_format,
+                chunk,
+                luts,
+                &mut packet[PACKET_HEADER_SIZE..],
+            );
+            encoded += 1;
+        }
+        self.luts = Some(*luts);
+        encoded
+    }
+
+    pub fn packets(&self) -> impl Iterator<Item = &[u8]> {
+        self.packets.chunks_exact(PACKET_SIZE)
+    }
+}
+
+fn encode_payload(
+    color_format: ColorFormat,
+    chunk: &[Rgb<u8>],
+    luts: &[[u8; 256]; 3],
+    payload: &mut [u8],
+) {
+    let mut bytes = payload.chunks_exact_mut(BYTES_PER_PIXEL);
+    for (pixel, bytes) in chunk.iter().zip(&mut bytes) {
+        let corrected = Rgb([
+            luts[0][pixel[0] as usize],
+            luts[1][pixel[1] as usize],
+            luts[2][pixel[2] as usize],
+        ]);
+        bytes.copy_from_slice(&pixel_to_bytes(color_format, &corrected));
+    }
+    // The last chunk of a frame is usually shorter than a full payload
+    for bytes in bytes {
+        bytes.fill(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::{Brightness, Calibration};
+    use crate::linsn::LinsnSenderPacket;
+    use pnet::packet::Packet;
+
+    fn identity_luts() -> [[u8; 256]; 3] {
+        Calibration::default().channel_luts(&Brightness::default())
+    }
+
+    fn encoded_packet(encoder: &FrameEncoder, package_id: usize) -> &[u8] {
+        encoder.packets().nth(package_id).expect("No such packet")
+    }
+
+    #[test]
+    fn test_matches_packet_builder() {
+        let geometry = FrameGeometry::new(64, 20, 1);
+        let src_mac = MacAddr::new(0x02, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let dst_mac = MacAddr::broadcast();
+        let image: Vec<Rgb<u8>> = (0..geometry.pixel_count())
+            .map(|i| Rgb([i as u8, (i >> 8) as u8, 0x42]))
+            .collect();
+
+        let mut encoder = FrameEncoder::new(geometry, src_mac, dst_mac, ColorFormat::GRB);
+        encoder.encode_damaged(&image, &identity_luts(), &Damage::full());
+        assert_eq!(encoder.packets().count(), 3);
+
+        for (package_id, chunk) in image.chunks(CHUNK_SIZE).enumerate() {
+            let mut payload = [0u8; PAYLOAD_SIZE_SENDER];
+            for (pixel, bytes) in chunk.iter().zip(payload.chunks_exact_mut(BYTES_PER_PIXEL)) {
+                bytes.copy_from_slice(&pixel_to_bytes(ColorFormat::GRB, pixel));
+            }
+            let packet = LinsnSenderPacket {
+                header: match package_id {
+                    0 => LinsnHeader::chunk_start(src_mac),
+                    _ => LinsnHeader::empty(package_id as u32),
+                },
+                payload,
+            };
+            let expected = packet.as_ethernet(Some(src_mac), Some(dst_mac));
+            assert_eq!(encoded_packet(&encoder, package_id), expected.packet());
+        }
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let geometry = FrameGeometry::new(64, 20, 0);
+        let mut encoder = FrameEncoder::new(
+            geometry,
+            MacAddr::zero(),
+            MacAddr::zero(),
+            ColorFormat::RGB,
+        );
+        encoder.encode_damaged(&vec![Rgb([1, 2, 3]); geometry.pixel_count()], &identity_luts(), &Damage::full());
+
+        encoder.configure(geometry, MacAddr::zero(), MacAddr::broadcast(), ColorFormat::RGB);
+        assert_eq!(&encoded_packet(&encoder, 1)[0..6], &[0xFF; 6]);
+        // Payloads survive a header update
+        assert_eq!(&encoded_packet(&encoder, 1)[PACKET_HEADER_SIZE..][..3], &[1, 2, 3]);
+
+        let larger = FrameGeometry::new(128, 20, 0);
+        encoder.configure(larger, MacAddr::zero(), MacAddr::broadcast(), ColorFormat::RGB);
+        assert_eq!(encoder.packets().count(), 6);
+        assert_eq!(&encoded_packet(&encoder, 5)[14..18], &5u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_encodes_only_damaged_chunks() {
+        let geometry = FrameGeometry::new(64, 20, 0);
+        let mut encoder = FrameEncoder::new(
+            geometry,
+            MacAddr::zero(),
+            MacAddr::zero(),
+            ColorFormat::RGB,
+        );
+        let mut image = vec![Rgb([1, 2, 3]); geometry.pixel_count()];
+        let luts = identity_luts();
+        assert_eq!(encoder.encode_damaged(&image, &luts, &Damage::default()), 3);
+
+        image[CHUNK_SIZE] = Rgb([4, 5, 6]);
+        image[0] = Rgb([7, 8, 9]);
+        let mut damage = Damage::default();
+        damage.mark_pixel(CHUNK_SIZE);
+        assert_eq!(encoder.encode_damaged(&image, &luts, &damage), 1);
+        assert_eq!(&encoded_packet(&encoder, 1)[PACKET_HEADER_SIZE..][..3], &[4, 5, 6]);
+        // Not marked, so the cached bytes are sent
+        assert_eq!(&encoded_packet(&encoder, 0)[PACKET_HEADER_SIZE..][..3], &[1, 2, 3]);
+
+        // New tables, e.g. after a brightness change, invalidate the cache
+        let mut dimmed = luts;
+        dimmed[0][7] = 0;
+        assert_eq!(encoder.encode_damaged(&image, &dimmed, &Damage::default()), 3);
+        assert_eq!(&encoded_packet(&encoder, 0)[PACKET_HEADER_SIZE..][..3], &[0, 8, 9]);
+    }
+
+    #[test]
+    fn test_damage_merge() {
+        let mut damage = Damage::default();
+        assert!(damage.is_empty());
+        let mut other = Damage::default();
+        other.mark_chunk(3);
+        damage.merge(&other);
+        assert!(damage.is_dirty(3) && !damage.is_dirty(2));
+        assert!(!damage.take().is_empty());
+        assert!(damage.is_empty());
+        damage.merge(&Damage::full());
+        assert!(damage.is_dirty(1000));
+    }
+}
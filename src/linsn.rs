@@ -1,5 +1,8 @@
+use std::fmt;
+use std::str::FromStr;
+
 use pnet::{
-    packet::ethernet::{EtherType, MutableEthernetPacket},
+    packet::ethernet::{EtherType, EthernetPacket, MutableEthernetPacket},
     util::MacAddr,
 };
 
@@ -7,12 +10,89 @@ pub const HEADER_SIZE: usize = 0x20;
 pub const ETHERNET_TYPE_SENDER: u16 = 0xAA55_u16;
 pub const PAYLOAD_SIZE_SENDER: usize = 1440;
 
+pub const BYTES_PER_PIXEL: usize = 3;
+// Pixels carried by a single sender packet
+pub const CHUNK_SIZE: usize = PAYLOAD_SIZE_SENDER / BYTES_PER_PIXEL;
+
 pub const ETHERNET_TYPE_RECEIVER: u16 = 0xAA56_u16;
 pub const PAYLOAD_SIZE_RECEIVER: usize = 1450;
 
 pub const LINSN_FRAME_WIDTH: u32 = 1024;
 pub const LINSN_FRAME_HEIGHT: u32 = 512;
+// The first row of the sender-card canvas is not shown on the panels
+pub const LINSN_ROW_OFFSET: u32 = 1;
+
+// Size of the canvas a sender card transmits and where the panel content starts
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FrameGeometry {
+    pub width: u32,
+    pub height: u32,
+    pub row_offset: u32,
+}
+
+impl FrameGeometry {
+    pub fn new(width: u32, height: u32, row_offset: u32) -> Self {
+        FrameGeometry {
+            width,
+            height,
+            row_offset,
+        }
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.pixel_count().div_ceil(CHUNK_SIZE)
+    }
+
+    // Position of a panel pixel in the canvas, None if it falls outside
+    pub fn index(&self, x: u32, y: u32) -> Option<usize> {
+        let y = y.checked_add(self.row_offset)?;
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        Some(y as usize * self.width as usize + x as usize)
+    }
+}
+
+// Parses WIDTHxHEIGHT with an optional +ROW_OFFSET, e.g. "1024x512+1"
+impl FromStr for FrameGeometry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid geometry '{}', expected WIDTHxHEIGHT[+ROW_OFFSET]",
+                s
+            )
+        };
+        let (size, row_offset) = match s.split_once('+') {
+            Some((size, offset)) => (size, offset.parse().map_err(|_| invalid())?),
+            None => (s, LINSN_ROW_OFFSET),
+        };
+        let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+        let geometry = FrameGeometry::new(
+            width.parse().map_err(|_| invalid())?,
+            height.parse().map_err(|_| invalid())?,
+            row_offset,
+        );
+        if geometry.pixel_count() == 0 || geometry.row_offset >= geometry.height {
+            return Err(invalid());
+        }
+        Ok(geometry)
+    }
+}
 
+impl Default for FrameGeometry {
+    fn default() -> Self {
+        FrameGeometry::new(LINSN_FRAME_WIDTH, LINSN_FRAME_HEIGHT, LINSN_ROW_OFFSET)
+    }
+}
+
+// Named like in the protocol notes
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone)]
 #[repr(u8)]
 pub enum LinsnCommand {
@@ -21,35 +101,90 @@ pub enum LinsnCommand {
     ANNOUNCE = 0x96u8,
 }
 
+// Byte order in which the LED modules expect the color channels
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub enum ColorFormat {
     RGB,
+    RBG,
+    GRB,
     GBR,
+    #[default]
     BRG,
     BGR,
 }
 
+impl FromStr for ColorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "RGB" => Ok(ColorFormat::RGB),
+            "RBG" => Ok(ColorFormat::RBG),
+            "GRB" => Ok(ColorFormat::GRB),
+            "GBR" => Ok(ColorFormat::GBR),
+            "BRG" => Ok(ColorFormat::BRG),
+            "BGR" => Ok(ColorFormat::BGR),
+            _ => Err(format!("unknown color format '{}'", s)),
+        }
+    }
+}
+
 pub fn pixel_to_bytes(format: ColorFormat, pixel: &image::Rgb<u8>) -> [u8; 3] {
     match format {
         ColorFormat::GBR => [pixel[1], pixel[2], pixel[0]],
         ColorFormat::RGB => [pixel[0], pixel[1], pixel[2]],
+        ColorFormat::RBG => [pixel[0], pixel[2], pixel[1]],
+        ColorFormat::GRB => [pixel[1], pixel[0], pixel[2]],
         ColorFormat::BRG => [pixel[2], pixel[0], pixel[1]],
         ColorFormat::BGR => [pixel[2], pixel[1], pixel[0]],
     }
 }
 
-pub fn rgb_to_bytes(format: ColorFormat, red: u16, green: u16, blue: u16) -> [u8; 3] {
-    let r = (red & 0xFF) as u8;
-    let g = (green & 0xFF) as u8;
-    let b = (blue & 0xFF) as u8;
-
+pub fn bytes_to_pixel(format: ColorFormat, bytes: &[u8]) -> image::Rgb<u8> {
     match format {
-        ColorFormat::GBR => [g, b, r],
-        ColorFormat::RGB => [r, g, b],
-        ColorFormat::BRG => [b, r, g],
-        ColorFormat::BGR => [b, g, r],
+        ColorFormat::GBR => image::Rgb([bytes[2], bytes[0], bytes[1]]),
+        ColorFormat::RGB => image::Rgb([bytes[0], bytes[1], bytes[2]]),
+        ColorFormat::RBG => image::Rgb([bytes[0], bytes[2], bytes[1]]),
+        ColorFormat::GRB => image::Rgb([bytes[1], bytes[0], bytes[2]]),
+        ColorFormat::BRG => image::Rgb([bytes[1], bytes[2], bytes[0]]),
+        ColorFormat::BGR => image::Rgb([bytes[2], bytes[1], bytes[0]]),
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LinsnParseError {
+    WrongLength { expected: usize, actual: usize },
+    WrongEtherType(u16),
+    BadChecksum { expected: u8, actual: u8 },
+}
+
+impl fmt::Display for LinsnParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinsnParseError::WrongLength { expected, actual } => {
+                write!(
+                    f,
+                    "wrong length: expected {} bytes, got {}",
+                    expected, actual
+                )
+            }
+            LinsnParseError::WrongEtherType(ether_type) => {
+                write!(f, "wrong EtherType {:#06x}", ether_type)
+            }
+            LinsnParseError::BadChecksum { expected, actual } => {
+                write!(
+                    f,
+                    "bad header checksum: expected {:#04x}, got {:#04x}",
+                    expected, actual
+                )
+            }
+        }
     }
 }
 
+impl std::error::Error for LinsnParseError {}
+
 #[derive(Debug, Copy, Clone)]
 pub struct LinsnSenderPacket {
     pub header: LinsnHeader,
@@ -59,9 +194,36 @@ pub struct LinsnSenderPacket {
 #[derive(Debug, Copy, Clone)]
 pub struct LinsnReceiverPacket {
     pub header: LinsnHeader,
+    // The content of replies is not understood yet
+    #[allow(dead_code)]
     pub payload: [u8; PAYLOAD_SIZE_RECEIVER],
 }
 
+// The known part of a CONFIG command: like ANNOUNCE, the sender MAC occupies the
+// last six bytes of cmd_data. The settings in front of it are not documented and
+// are passed through as captured from the vendor software.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LinsnConfig {
+    pub settings: [u8; 16],
+    pub sender_mac: MacAddr,
+}
+
+impl LinsnConfig {
+    pub fn new(settings: [u8; 16], sender_mac: MacAddr) -> Self {
+        LinsnConfig {
+            settings,
+            sender_mac,
+        }
+    }
+
+    pub fn to_cmd_data(self) -> [u8; 22] {
+        let mut cmd_data = [0u8; 22];
+        cmd_data[..16].copy_from_slice(&self.settings);
+        cmd_data[16..].copy_from_slice(&self.sender_mac.octets());
+        cmd_data
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct LinsnHeader {
     pub package_id: u32,
@@ -97,6 +259,32 @@ impl LinsnHeader {
         (0x100 - (checksum & 0xFF)) as u8
     }
 
+    pub fn is_checksum_valid(&self) -> bool {
+        self.checksum == LinsnHeader::calculate_checksum(self.unknown, self.cmd, self.cmd_data)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LinsnParseError> {
+        check_length(bytes, HEADER_SIZE)?;
+        let header = LinsnHeader {
+            package_id: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            unknown: bytes[4..8].try_into().unwrap(),
+            cmd: bytes[8],
+            cmd_data: bytes[9..31].try_into().unwrap(),
+            checksum: bytes[31],
+        };
+        if !header.is_checksum_valid() {
+            return Err(LinsnParseError::BadChecksum {
+                expected: LinsnHeader::calculate_checksum(
+                    header.unknown,
+                    header.cmd,
+                    header.cmd_data,
+                ),
+                actual: header.checksum,
+            });
+        }
+        Ok(header)
+    }
+
     pub fn to_bytes(self) -> Vec<u8> {
         let mut bytes = vec![];
         bytes.extend_from_slice(&self.package_id.to_le_bytes());
@@ -150,6 +338,15 @@ impl LinsnHeader {
     pub fn chunk_start(sender_mac: MacAddr) -> Self {
         LinsnHeader::identify(0, sender_mac)
     }
+
+    pub fn config(package_id: u32, config: &LinsnConfig) -> Self {
+        LinsnHeader::config_raw(package_id, config.to_cmd_data())
+    }
+
+    // Sends cmd_data exactly as given, e.g. replayed from a capture
+    pub fn config_raw(package_id: u32, cmd_data: [u8; 22]) -> Self {
+        LinsnHeader::new(package_id, LinsnCommand::CONFIG as u8, cmd_data)
+    }
 }
 
 impl LinsnSenderPacket {
@@ -159,7 +356,7 @@ impl LinsnSenderPacket {
         bytes.extend_from_slice(&self.payload);
         bytes
     }
-    pub fn as_ethernet(&self, src: Option<MacAddr>, dst: Option<MacAddr>) -> MutableEthernetPacket {
+    pub fn as_ethernet(&self, src: Option<MacAddr>, dst: Option<MacAddr>) -> MutableEthernetPacket<'_> {
         let src_mac = match src {
             Some(mac) => mac,
             None => MacAddr::broadcast(),
@@ -182,11 +379,91 @@ impl LinsnSenderPacket {
 
         ethernet_packet
     }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LinsnParseError> {
+        check_length(bytes, HEADER_SIZE + PAYLOAD_SIZE_SENDER)?;
+        Ok(LinsnSenderPacket {
+            header: LinsnHeader::from_bytes(&bytes[..HEADER_SIZE])?,
+            payload: bytes[HEADER_SIZE..].try_into().unwrap(),
+        })
+    }
+
+    pub fn from_ethernet(frame: &[u8]) -> Result<Self, LinsnParseError> {
+        let payload = ethernet_payload(frame, ETHERNET_TYPE_SENDER)?;
+        LinsnSenderPacket::from_bytes(truncate(payload, HEADER_SIZE + PAYLOAD_SIZE_SENDER))
+    }
+}
+
+impl LinsnReceiverPacket {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LinsnParseError> {
+        check_length(bytes, HEADER_SIZE + PAYLOAD_SIZE_RECEIVER)?;
+        Ok(LinsnReceiverPacket {
+            header: LinsnHeader::from_bytes(&bytes[..HEADER_SIZE])?,
+            payload: bytes[HEADER_SIZE..].try_into().unwrap(),
+        })
+    }
+
+    pub fn from_ethernet(frame: &[u8]) -> Result<Self, LinsnParseError> {
+        let payload = ethernet_payload(frame, ETHERNET_TYPE_RECEIVER)?;
+        LinsnReceiverPacket::from_bytes(truncate(payload, HEADER_SIZE + PAYLOAD_SIZE_RECEIVER))
+    }
+}
+
+impl TryFrom<&[u8]> for LinsnHeader {
+    type Error = LinsnParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        LinsnHeader::from_bytes(bytes)
+    }
+}
+
+impl TryFrom<&[u8]> for LinsnSenderPacket {
+    type Error = LinsnParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        LinsnSenderPacket::from_bytes(bytes)
+    }
+}
+
+impl TryFrom<&[u8]> for LinsnReceiverPacket {
+    type Error = LinsnParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        LinsnReceiverPacket::from_bytes(bytes)
+    }
+}
+
+fn check_length(bytes: &[u8], expected: usize) -> Result<(), LinsnParseError> {
+    if bytes.len() != expected {
+        return Err(LinsnParseError::WrongLength {
+            expected,
+            actual: bytes.len(),
+        });
+    }
+    Ok(())
+}
+
+fn ethernet_payload(frame: &[u8], ether_type: u16) -> Result<&[u8], LinsnParseError> {
+    let ethernet = EthernetPacket::new(frame).ok_or(LinsnParseError::WrongLength {
+        expected: EthernetPacket::minimum_packet_size(),
+        actual: frame.len(),
+    })?;
+    if ethernet.get_ethertype().0 != ether_type {
+        return Err(LinsnParseError::WrongEtherType(ethernet.get_ethertype().0));
+    }
+    let offset = EthernetPacket::minimum_packet_size();
+    Ok(&frame[offset..])
+}
+
+// Captures can carry padding or the frame check sequence after the Linsn data
+fn truncate(bytes: &[u8], len: usize) -> &[u8] {
+    &bytes[..bytes.len().min(len)]
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use pnet::packet::Packet;
 
     #[test]
     fn test_checksum_in_new() {
@@ -265,4 +542,182 @@ mod tests {
             "Byte representation does not match expected value"
         );
     }
+
+    #[test]
+    fn test_config_to_bytes() {
+        let settings: [u8; 16] = [
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E,
+            0x0F, 0x10,
+        ];
+        let sender_mac = MacAddr::new(0x02, 0x11, 0x22, 0x33, 0x44, 0x55);
+
+        let expected_bytes: [u8; 32] = [
+            0x05, 0x00, 0x00, 0x00, // package_id
+            0x00, 0x00, 0x00, 0x00, // unknown
+            0x61, // cmd
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E,
+            0x0F, 0x10, // settings
+            0x02, 0x11, 0x22, 0x33, 0x44, 0x55, // sender mac
+            0x16, // checksum
+        ];
+
+        let config = LinsnConfig::new(settings, sender_mac);
+        let header = LinsnHeader::config(5, &config);
+        assert_eq!(
+            header.to_bytes(),
+            expected_bytes,
+            "Byte representation does not match expected value"
+        );
+        assert!(LinsnHeader::from_bytes(&expected_bytes).is_ok());
+    }
+
+    #[test]
+    fn test_config_raw_to_bytes() {
+        let cmd_data = [0xA5u8; 22];
+
+        let mut expected_bytes = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x61];
+        expected_bytes.extend_from_slice(&cmd_data);
+        expected_bytes.push(0x71);
+
+        let header = LinsnHeader::config_raw(0, cmd_data);
+        assert_eq!(
+            header.to_bytes(),
+            expected_bytes,
+            "Byte representation does not match expected value"
+        );
+        assert!(LinsnHeader::from_bytes(&expected_bytes).is_ok());
+    }
+
+    #[test]
+    fn test_receiver_packet_from_bytes() {
+        let header = LinsnHeader::new(0x0102, LinsnCommand::ANNOUNCE as u8, [0x42u8; 22]);
+        let mut bytes = header.to_bytes();
+        bytes.extend_from_slice(&[0x55u8; PAYLOAD_SIZE_RECEIVER]);
+
+        let packet = LinsnReceiverPacket::from_bytes(&bytes).expect("Failed to parse packet");
+        assert_eq!(packet.header.package_id, 0x0102);
+        assert_eq!(packet.header.cmd, LinsnCommand::ANNOUNCE as u8);
+        assert_eq!(packet.header.cmd_data, [0x42u8; 22]);
+        assert!(packet.header.is_checksum_valid());
+        assert_eq!(packet.payload, [0x55u8; PAYLOAD_SIZE_RECEIVER]);
+
+        bytes[12] ^= 0xFF;
+        assert!(matches!(
+            LinsnReceiverPacket::from_bytes(&bytes),
+            Err(LinsnParseError::BadChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bytes_to_pixel_inverts_pixel_to_bytes() {
+        let pixel = image::Rgb([0x11, 0x22, 0x33]);
+        for format in [
+            ColorFormat::RGB,
+            ColorFormat::RBG,
+            ColorFormat::GRB,
+            ColorFormat::GBR,
+            ColorFormat::BRG,
+            ColorFormat::BGR,
+        ] {
+            let bytes = pixel_to_bytes(format, &pixel);
+            assert_eq!(bytes_to_pixel(format, &bytes), pixel, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_color_format_from_str() {
+        assert_eq!("grb".parse::<ColorFormat>(), Ok(ColorFormat::GRB));
+        assert_eq!("RBG".parse::<ColorFormat>(), Ok(ColorFormat::RBG));
+        assert!("RGBW".parse::<ColorFormat>().is_err());
+    }
+
+    #[test]
+    fn test_frame_geometry() {
+        let geometry = FrameGeometry::default();
+        assert_eq!(geometry.chunk_count(), 1093);
+        assert_eq!(geometry.index(0, 0), Some(1024));
+        assert_eq!(geometry.index(5, 510), Some(511 * 1024 + 5));
+        assert_eq!(geometry.index(5, 511), None);
+        assert_eq!(geometry.index(1024, 0), None);
+
+        let geometry: FrameGeometry = "256x128+0".parse().unwrap();
+        assert_eq!(geometry, FrameGeometry::new(256, 128, 0));
+        assert_eq!(geometry.chunk_count(), 69);
+        assert_eq!(geometry.index(3, 2), Some(2 * 256 + 3));
+
+        assert_eq!("1024x512".parse(), Ok(FrameGeometry::default()));
+        assert!("1024".parse::<FrameGeometry>().is_err());
+        assert!("64x2+2".parse::<FrameGeometry>().is_err());
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let sender_mac = MacAddr::new(0x02, 0x11, 0x22, 0x33, 0x44, 0x55);
+        for header in [
+            LinsnHeader::chunk_start(sender_mac),
+            LinsnHeader::identify(7, sender_mac),
+            LinsnHeader::empty(1092),
+            LinsnHeader::new(0x12345678, 0xAA, [0x10u8; 22]),
+        ] {
+            let bytes = header.to_bytes();
+            let parsed = LinsnHeader::try_from(bytes.as_slice()).unwrap();
+            assert_eq!(parsed.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_sender_packet_round_trip() {
+        let src_mac = MacAddr::new(0x02, 0x11, 0x22, 0x33, 0x44, 0x55);
+        let dst_mac = MacAddr::new(0x02, 0x66, 0x77, 0x88, 0x99, 0xAA);
+        let mut payload = [0u8; PAYLOAD_SIZE_SENDER];
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let packet = LinsnSenderPacket {
+            header: LinsnHeader::chunk_start(src_mac),
+            payload,
+        };
+
+        let bytes = packet.to_bytes();
+        let parsed = LinsnSenderPacket::try_from(bytes.as_slice()).unwrap();
+        assert_eq!(parsed.to_bytes(), bytes);
+
+        let ethernet = packet.as_ethernet(Some(src_mac), Some(dst_mac));
+        let parsed = LinsnSenderPacket::from_ethernet(ethernet.packet()).unwrap();
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let packet = LinsnSenderPacket {
+            header: LinsnHeader::identify(3, MacAddr::zero()),
+            payload: [0u8; PAYLOAD_SIZE_SENDER],
+        };
+        let mut bytes = packet.to_bytes();
+
+        assert_eq!(
+            LinsnSenderPacket::from_bytes(&bytes[1..]).unwrap_err(),
+            LinsnParseError::WrongLength {
+                expected: HEADER_SIZE + PAYLOAD_SIZE_SENDER,
+                actual: HEADER_SIZE + PAYLOAD_SIZE_SENDER - 1,
+            }
+        );
+
+        let checksum = bytes[HEADER_SIZE - 1];
+        bytes[HEADER_SIZE - 1] = checksum.wrapping_add(1);
+        assert_eq!(
+            LinsnSenderPacket::from_bytes(&bytes).unwrap_err(),
+            LinsnParseError::BadChecksum {
+                expected: checksum,
+                actual: checksum.wrapping_add(1),
+            }
+        );
+
+        let mut ethernet = packet.as_ethernet(None, None);
+        ethernet.set_ethertype(EtherType(ETHERNET_TYPE_RECEIVER));
+        assert_eq!(
+            LinsnSenderPacket::from_ethernet(ethernet.packet()).unwrap_err(),
+            LinsnParseError::WrongEtherType(ETHERNET_TYPE_RECEIVER)
+        );
+    }
 }
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use pnet::datalink;
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::util::MacAddr;
+
+use crate::linsn::{LinsnParseError, LinsnReceiverPacket, ETHERNET_TYPE_RECEIVER};
+use crate::socket::{is_link_error, open_receiver, SenderError, RECEIVE_RETRY_INTERVAL};
+
+#[derive(Debug, Clone)]
+pub struct ReceiverCard {
+    pub mac: MacAddr,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    pub replies: u64,
+    pub last_packet: LinsnReceiverPacket,
+}
+
+impl ReceiverCard {
+    pub fn is_alive(&self, timeout: Duration) -> bool {
+        self.last_seen.elapsed() <= timeout
+    }
+}
+
+// Collects the 0xAA56 replies of all receiver cards seen on an interface.
+// Replies with a broken header checksum are counted and dropped.
+pub struct ReceiverListener {
+    cards: Arc<Mutex<HashMap<MacAddr, ReceiverCard>>>,
+    bad_checksums: Arc<AtomicU64>,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ReceiverListener {
+    pub fn new(interface_name: &str) -> Result<Self, SenderError> {
+        // The timeout lets the listener thread notice when it should stop
+        let config = datalink::Config {
+            read_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let mut rx = open_receiver(interface_name, config)?;
+
+        let cards = Arc::new(Mutex::new(HashMap::new()));
+        let bad_checksums = Arc::new(AtomicU64::new(0));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let handle = thread::spawn({
+            let cards = Arc::clone(&cards);
+            let bad_checksums = Arc::clone(&bad_checksums);
+            let running = Arc::clone(&running);
+            move || {
+                while running.load(Ordering::Relaxed) {
+                    let frame = match rx.next() {
+                        Ok(frame) => frame,
+                        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+                        Err(e) if is_link_error(&e) => {
+                            eprintln!("Stopped listening: {}", e);
+                            return;
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to receive packet: {}", e);
+                            thread::sleep(RECEIVE_RETRY_INTERVAL);
+                            continue;
+                        }
+                    };
+                    match parse_reply(frame) {
+                        Some((mac, Ok(packet))) => record_reply(&cards, mac, packet),
+                        Some((_, Err(LinsnParseError::BadChecksum { .. }))) => {
+                            bad_checksums.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Some((mac, Err(e))) => eprintln!("Invalid reply from {}: {}", mac, e),
+                        None => (),
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            cards,
+            bad_checksums,
+            running,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn cards(&self) -> Vec<ReceiverCard> {
+        let cards = self.cards.lock().expect("Mutex Poisend");
+        let mut cards: Vec<ReceiverCard> = cards.values().cloned().collect();
+        cards.sort_by_key(|card| card.first_seen);
+        cards
+    }
+
+    pub fn alive_cards(&self, timeout: Duration) -> Vec<ReceiverCard> {
+        self.cards()
+            .into_iter()
+            .filter(|card| card.is_alive(timeout))
+            .collect()
+    }
+
+    pub fn bad_checksums(&self) -> u64 {
+        self.bad_checksums.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ReceiverListener {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Returns None for anything that is not a receiver card reply
+fn parse_reply(frame: &[u8]) -> Option<(MacAddr, Result<LinsnReceiverPacket, LinsnParseError>)> {
+    let ethernet = EthernetPacket::new(frame)?;
+    if ethernet.get_ethertype().0 != ETHERNET_TYPE_RECEIVER {
+        return None;
+    }
+    Some((
+        ethernet.get_source(),
+        LinsnReceiverPacket::from_ethernet(frame),
+    ))
+}
+
+fn record_reply(
+    cards: &Mutex<HashMap<MacAddr, ReceiverCard>>,
+    mac: MacAddr,
+    packet: LinsnReceiverPacket,
+) {
+    let now = Instant::now();
+    let mut cards = cards.lock().expect("Mutex Poisend");
+    let card = cards.entry(mac).or_insert(ReceiverCard {
+        mac,
+        first_seen: now,
+        last_seen: now,
+        replies: 0,
+        last_packet: packet,
+    });
+    card.last_seen = now;
+    card.replies += 1;
+    card.last_packet = packet;
+}
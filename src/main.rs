@@ -1,204 +1,506 @@
-use std::fs;
-use std::sync::atomic::AtomicBool;
+use std::sync::mpsc;
 use std::sync::Arc;
+#[cfg(feature = "screen-capture")]
 use std::sync::Mutex;
+use std::fmt;
+use std::path::Path;
 use std::time::Duration;
 use std::time::Instant;
 
+#[cfg(feature = "screen-capture")]
 use gstreamer::BufferRef;
-use image::imageops::resize;
-use image::DynamicImage;
-use image::ImageBuffer;
 use image::Rgb;
-use libc::size_t;
-use linsn::LINSN_FRAME_HEIGHT;
-use linsn::LINSN_FRAME_WIDTH;
+#[cfg(feature = "screen-capture")]
+use image::Rgba;
+use pnet::packet::Packet;
 use pnet::util::MacAddr;
 use primitives::Panel;
-use rand::prelude::*;
-use screen_capture::init_gstreamer;
+use cli::{Cli, Command, OutputArgs, SenderBackend};
+use clap::{CommandFactory, Parser, ValueEnum};
+use test_pattern::{draw_test_pattern, TestPattern};
+#[cfg(feature = "screen-capture")]
+use screen_capture::{init_gstreamer, wait_for_end, CaptureSource};
 use socket::BatchedSocketSender;
+use socket::ChannelSender;
 use socket::LinsnSocket;
+use socket::PcapFileSender;
+use socket::RingSocketSender;
+use socket::send_frame;
+use socket::interface_mac;
+use socket::SenderError;
+use socket::SenderSettings;
 use socket::SimpleSocketSender;
-use sprite::load_image_directory;
-use sprite::AnimatedPath;
-use sprite::AnimatedSprite;
+use listener::ReceiverListener;
+use color::{Brightness, Calibration};
+use linsn::{LinsnConfig, LinsnHeader, LinsnSenderPacket, PAYLOAD_SIZE_SENDER};
+use mapping::PixelMap;
+use virtual_receiver::VirtualReceiver;
+use replay::replay_pcap;
+use reload::SceneReloader;
+use scene::Scene;
 use std::thread;
 
+mod animated_image;
+mod aseprite;
+mod cli;
+mod color;
+mod encoder;
 mod linsn;
+mod listener;
+mod mapping;
+mod pcap;
+mod reload;
+mod replay;
+mod scene;
+mod scheduler;
 mod primitives;
+#[cfg(feature = "screen-capture")]
 mod screen_capture;
 mod socket;
 mod sprite;
-
-const PANEL_X: usize = 192;
-const PANEL_Y: usize = 192;
+mod test_pattern;
+#[cfg(test)]
+mod test_util;
+mod virtual_receiver;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <interface_name>", args[0]);
-        return;
+    let cli = Cli::parse();
+    let output = &cli.output;
+    let interface_name = || {
+        cli.interface.as_deref().unwrap_or_else(|| {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "this command needs --interface",
+                )
+                .exit()
+        })
+    };
+    let endpoint = || match output.pcap.as_deref() {
+        Some(path) => Endpoint::Capture(path),
+        None => Endpoint::Interface(interface_name()),
+    };
+
+    match &cli.command {
+        Command::ListInterfaces => list_interfaces(),
+        Command::Listen => list_receiver_cards(interface_name()),
+        Command::Receive { snapshot } => {
+            receive_frames(endpoint(), output, snapshot.as_deref())
+        }
+        Command::Bench { frames } => benchmark_senders(interface_name(), output, *frames),
+        Command::Replay { capture, speed } => replay(interface_name(), capture, *speed),
+        Command::Config {
+            settings,
+            package_id,
+        } => send_config(interface_name(), output, *package_id, *settings),
+        Command::Run { scene } => {
+            let scene = match Scene::load(scene) {
+                Ok(scene) => scene,
+                Err(e) => {
+                    eprintln!("{}: {}", scene.display(), e);
+                    return;
+                }
+            };
+            if let Some(panel) = open_output(endpoint(), output) {
+                run_scene(panel, scene, output.refresh_rate);
+            }
+        }
+        Command::Preview { scene, image } => preview_scene(scene, output, image),
+        Command::TestPattern { pattern } => {
+            if let Some(panel) = open_output(endpoint(), output) {
+                show_test_pattern(panel, *pattern);
+            }
+        }
+        #[cfg(feature = "screen-capture")]
+        Command::Play { video } => {
+            if let Some(panel) = open_output(endpoint(), output) {
+                play_video(panel, CaptureSource::File(video.clone()));
+            }
+        }
+        #[cfg(feature = "screen-capture")]
+        Command::CaptureScreen => {
+            if let Some(panel) = open_output(endpoint(), output) {
+                play_video(panel, CaptureSource::Screen);
+            }
+        }
+    }
+}
+
+// Where frames are sent to and received from
+enum Endpoint<'a> {
+    Interface(&'a str),
+    // Capture file given with --pcap, allows running without a network interface
+    Capture(&'a Path),
+}
+
+impl fmt::Display for Endpoint<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Interface(name) => write!(f, "{}", name),
+            Endpoint::Capture(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+// Opens the sender and starts sending the panel at the configured refresh rate
+fn open_output(endpoint: Endpoint, output: &OutputArgs) -> Option<Panel> {
+    let settings = sender_settings(output)?;
+    spawn_brightness_control(settings.brightness.clone());
+    let sender = match open_sender(&endpoint, output.backend, settings) {
+        Ok(sender) => sender,
+        Err(e) => {
+            eprintln!("{}: {}", endpoint, e);
+            return None;
+        }
+    };
+
+    let mut panel = create_panel(output, true)?;
+    panel.start_output(sender, output.dst_mac, output.output_config());
+    Some(panel)
+}
+
+fn sender_settings(output: &OutputArgs) -> Option<SenderSettings> {
+    let calibration = match &output.calibration {
+        Some(path) => match Calibration::load(path) {
+            Ok(calibration) => calibration,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                return None;
+            }
+        },
+        None => Calibration::default(),
+    };
+    Some(
+        SenderSettings::default()
+            .with_color_format(output.color_order)
+            .with_brightness(Brightness::from_percent(output.brightness))
+            .with_calibration(calibration)
+            .with_geometry(output.geometry()),
+    )
+}
+
+// Walls made of several cabinets are described by a mapping file
+fn create_panel(output: &OutputArgs, double_buffering: bool) -> Option<Panel> {
+    let geometry = output.geometry();
+    match &output.mapping {
+        Some(path) => match PixelMap::load(path, geometry) {
+            Ok(mapping) => Some(Panel::with_mapping(mapping, geometry, double_buffering, false)),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                None
+            }
+        },
+        None => Some(Panel::with_geometry(
+            output.panel_size.width,
+            output.panel_size.height,
+            geometry,
+            double_buffering,
+            false,
+        )),
     }
-    let interface_name = args[1].as_str();
-    let dst_mac = MacAddr::zero();
+}
+
+// Shows what the receiver cards would display, calibration and mapping included
+fn preview_scene(scene_path: &Path, output: &OutputArgs, image_path: &Path) {
+    let mut scene = match Scene::load(scene_path) {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("{}: {}", scene_path.display(), e);
+            return;
+        }
+    };
+    let (Some(settings), Some(mut panel)) = (sender_settings(output), create_panel(output, false)) else {
+        return;
+    };
 
-    let use_batched_sending = true;
+    let (tx, rx) = mpsc::channel();
+    let mut receiver = VirtualReceiver::with_geometry(output.color_order, settings.geometry);
+    let sender = Arc::new(ChannelSender::new(tx, settings));
+    scene.draw(&mut panel);
+    if let Err(e) = panel.send(sender, output.dst_mac) {
+        eprintln!("Failed to encode frame: {}", e);
+        return;
+    }
 
-    let sender: Arc<dyn LinsnSocket + Send> = if use_batched_sending {
-        Arc::new(BatchedSocketSender::new(interface_name))
-    } else {
-        Arc::new(SimpleSocketSender::new(interface_name))
+    let Some(frame) = receiver.decode_channel(&rx) else {
+        eprintln!("No frame was sent");
+        return;
     };
+    if !frame.is_complete() {
+        eprintln!("{} chunk(s) missing", frame.missing_chunks.len());
+    }
+    match frame.image.save(image_path) {
+        Ok(()) => println!("Saved {}", image_path.display()),
+        Err(e) => eprintln!("Failed to save {}: {}", image_path.display(), e),
+    }
+}
 
-    // init_gstreamer(play_demo_file, PANEL_X, PANEL_Y, {
-    //     let linsn_image_lock = Arc::clone(&inactive_buffer);
-    //     let should_flip = should_flip.clone();
-    //     move |buffer: &BufferRef, width: u32, height: u32, bytes_per_pixel: u32| {
-    //         // Map the buffer to read frame data
-    //         let map = buffer
-    //             .map_readable()
-    //             .expect("Failed to map buffer readable");
-
-    //         // Webcams often use Yuv2. We therefore enforve BGRx in gstreamer for now
-    //         let row_stride = (width * bytes_per_pixel) as usize;
-    //         let (copy_height, copy_width) =(height.min(PANEL_X as u32), width.min(PANEL_Y as u32));
-
-    //         let mut linsn_image = linsn_image_lock.lock().expect("Mutex Poisend");
-    //         for y in 0..copy_height as usize {
-    //             for x in 0..copy_width as usize {
-    //                 let offset = y * row_stride + x * bytes_per_pixel as usize;
-    //                 let b = map[offset] as u8;
-    //                 let g = map[offset + 1] as u8;
-    //                 let r = map[offset + 2] as u8;
-    //                 linsn_image[((y + 1) * LINSN_FRAME_WIDTH as usize) + x] = Pixel::new(r, g, b);
-    //             }
-    //         }
-
-    //         should_flip.store(true, std::sync::atomic::Ordering::Relaxed);
-    //     }
-    // });
-
-    // loop {
-    //     let mut active = active_buffer.lock().unwrap();
-
-    //     if should_flip.load(std::sync::atomic::Ordering::Relaxed) {
-    //         let mut inactive = inactive_buffer.lock().unwrap();
-    //         std::mem::swap(&mut *active, &mut *inactive);
-    //         should_flip.store(false, std::sync::atomic::Ordering::Relaxed);
-    //     }
-    //     sender.send(&active, dst_mac);
-    //     thread::sleep(Duration::from_millis(0));
-    // }
-
-    let mut rng = rand::rngs::ThreadRng::default();
-    let mut panel = Panel::new(192, 192, false, false);
-
-    // Train
-    let train_imgs = load_image_directory("./assets/train");
-    let mut train = AnimatedSprite::new(train_imgs.clone(), 1.0, sprite::LoopMode::PingPong, 2.0);
-
-    // Tracks
-    let tracks: DynamicImage = image::open("assets/train_tracks.png").unwrap();
-
-    // Background
-    let grass: DynamicImage = image::open("assets/grass.png").unwrap();
-    let backgrounds = load_image_directory("assets/background");
-
-    // Sky
-    let sky1 = load_image_directory("assets/sky");
-    let sky2 = load_image_directory("assets/sky/level2");
-    let sky3 = load_image_directory("assets/sky/level3");
-    let sky4 = load_image_directory("assets/sky/level4");
-
-    // Dragon
-    let dragon_imgs = load_image_directory("assets/dragon");
-    let mut dragon = AnimatedSprite::new(dragon_imgs, 2.5, sprite::LoopMode::PingPong, 2.0);
-    dragon.set_animation(  AnimatedPath::new_random(&mut rng, 16000));
-
-    // Schwebebahn
-    let schwebebahn = load_image_directory("assets/schwebebahn");
-
-    let mut skys_data = vec![sky4, sky3, sky2, sky1];
-    let mut skys = vec![vec![], vec![], vec![], vec![]];
+// Draws the scene, pacing rendering to the refresh rate of the output. Edits to
+// the scene file or its assets show up without a restart.
+fn run_scene(mut panel: Panel, mut scene: Scene, refresh_rate: f64) {
+    let mut reloader = SceneReloader::new(&scene);
     let before = Instant::now();
+    let frame_time = Duration::from_secs_f64(1.0 / refresh_rate);
+    let mut next_frame = before;
+    let mut reported_late = 0;
+    let mut last_report = before;
 
     loop {
-        let ts = Instant::now() - before;
-        panel.clear();
-
-        for i in 0..6 {
-            panel.draw_image((i * 32), 192 - 32, &grass, 2.0, false, false);
+        reloader.poll(&mut scene);
+        scene.draw(&mut panel);
+        panel.present();
+
+        // At most one report per second while the output keeps falling behind
+        if let Some(stats) = panel.output_stats().filter(|_| last_report.elapsed().as_secs() >= 1) {
+            if stats.frames_late > reported_late {
+                eprintln!(
+                    "Output fell behind: {} late, {} dropped, max send time {:.0?}",
+                    stats.frames_late, stats.frames_dropped, stats.max_send_time
+                );
+                reported_late = stats.frames_late;
+                last_report = Instant::now();
+            }
         }
 
-        for i in 0..4 {
-            train.draw_at(&mut panel, 64 * i, 192 - 32);
+        // There is no point in rendering more frames than the scheduler sends
+        next_frame += frame_time;
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
         }
+    }
+}
 
-        let track_offset = ((ts.as_millis() / 200) % 32) as i32;
-        for i in 0..8 {
-            panel.draw_image(
-                (i * 32) - track_offset,
-                192 - 32,
-                &tracks,
-                2.0,
-                false,
-                false,
-            );
+fn show_test_pattern(mut panel: Panel, pattern: TestPattern) {
+    draw_test_pattern(&mut panel, pattern);
+    panel.present();
+    // The output scheduler keeps sending the pattern, brightness changes included
+    loop {
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// Draws every decoded video frame onto the panel until the video ends
+#[cfg(feature = "screen-capture")]
+fn play_video(panel: Panel, source: CaptureSource) {
+    let (width, height) = (panel.width, panel.height);
+    let panel = Arc::new(Mutex::new(panel));
+    let pipeline = init_gstreamer(source, width, height, {
+        let panel = Arc::clone(&panel);
+        move |buffer: &BufferRef, width: u32, height: u32, bytes_per_pixel: u32| {
+            let map = buffer
+                .map_readable()
+                .expect("Failed to map buffer readable");
+
+            // Webcams often use Yuv2. We therefore enforve BGRx in gstreamer for now
+            let row_stride = (width * bytes_per_pixel) as usize;
+            let mut panel = panel.lock().expect("Mutex Poisend");
+            let (copy_width, copy_height) = (width.min(panel.width as u32), height.min(panel.height as u32));
+            for y in 0..copy_height as usize {
+                for x in 0..copy_width as usize {
+                    let offset = y * row_stride + x * bytes_per_pixel as usize;
+                    let pixel = Rgba([map[offset + 2], map[offset + 1], map[offset], 0xFF]);
+                    panel.set_pixel(x as i32, y as i32, pixel);
+                }
+            }
+            panel.present();
         }
+    });
+    wait_for_end(&pipeline);
+}
 
-        let background_offset = ((ts.as_millis() / 500) % 192) as i32;
-        for i in 0usize..16 {
-            panel.draw_image(
-                (i as i32 * 32) - background_offset,
-                192 - 64,
-                &backgrounds[i % backgrounds.len()],
-                2.0,
-                false,
-                false,
-            );
+fn replay(interface_name: &str, capture: &Path, speed: f64) {
+    let sender = match BatchedSocketSender::new(interface_name, SenderSettings::default()) {
+        Ok(sender) => sender,
+        Err(e) => {
+            eprintln!("{}: {}", interface_name, e);
+            return;
         }
+    };
+    match replay_pcap(capture, &sender, speed) {
+        Ok(stats) => println!(
+            "Replayed {} frames ({} skipped) in {:.0?}",
+            stats.frames_sent, stats.frames_skipped, stats.duration
+        ),
+        Err(e) => eprintln!("Replay failed: {}", e),
+    }
+}
 
-        let sky_offset = ((ts.as_millis() / 500) % 32) as i32;
-        for (i, (sky, sky_data)) in skys.iter_mut().zip(&skys_data).enumerate() {
-            while sky.len() < 8 {
-                sky.push(&sky_data[rng.next_u64() as usize % sky_data.len()]);
-            }
+fn send_config(interface_name: &str, output: &OutputArgs, package_id: u32, settings: [u8; 16]) {
+    // Same source MAC as the frames of the sender backends
+    let src_mac = match interface_mac(interface_name) {
+        Ok(mac) => mac,
+        Err(e) => {
+            eprintln!("{}: {}", interface_name, e);
+            return;
+        }
+    };
+    let packet = LinsnSenderPacket {
+        header: LinsnHeader::config(package_id, &LinsnConfig::new(settings, src_mac)),
+        payload: [0u8; PAYLOAD_SIZE_SENDER],
+    };
+    let frame = packet.as_ethernet(Some(src_mac), Some(output.dst_mac));
+    match send_frame(interface_name, frame.packet()) {
+        Ok(()) => println!("Sent CONFIG command {} to {}", package_id, output.dst_mac),
+        Err(e) => eprintln!("{}: {}", interface_name, e),
+    }
+}
 
-            let mut should_pop = false;
-            for (j, s) in sky.clone().into_iter().enumerate() {
-                let x = (j as i32 * 32) - sky_offset;
-                panel.draw_image(x, i as i32 * 32, s, 2.0, false, false);
+fn open_sender(
+    endpoint: &Endpoint,
+    backend: SenderBackend,
+    settings: SenderSettings,
+) -> Result<Arc<dyn LinsnSocket + Send + Sync>, SenderError> {
+    let interface_name = match endpoint {
+        Endpoint::Interface(name) => name,
+        Endpoint::Capture(path) => return Ok(Arc::new(PcapFileSender::new(path, settings)?)),
+    };
+    let sender: Arc<dyn LinsnSocket + Send + Sync> = match backend {
+        SenderBackend::Ring => Arc::new(RingSocketSender::new(interface_name, settings)?),
+        SenderBackend::Simple => Arc::new(SimpleSocketSender::new(interface_name, settings)?),
+        SenderBackend::Batched => Arc::new(BatchedSocketSender::new(interface_name, settings)?),
+    };
+    Ok(sender)
+}
 
-                if i == 0 {
-                    if x % 32 == 0 {
-                        should_pop = true;
-                    }
-                }
+// Sends the same frame with every backend and prints the achieved frame rate
+fn benchmark_senders(interface_name: &str, output: &OutputArgs, frames: u32) {
+    let geometry = output.geometry();
+    let image: Vec<Rgb<u8>> = (0..geometry.pixel_count())
+        .map(|i| Rgb([i as u8, (i >> 8) as u8, (i >> 16) as u8]))
+        .collect();
+
+    for backend in SenderBackend::value_variants() {
+        let name = backend.to_possible_value().unwrap().get_name().to_string();
+        let settings = SenderSettings::default().with_geometry(geometry);
+        let sender = match open_sender(&Endpoint::Interface(interface_name), *backend, settings) {
+            Ok(sender) => sender,
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                continue;
             }
+        };
 
-            if should_pop {
-                sky.pop();
+        let before = Instant::now();
+        let mut errors = 0;
+        for _ in 0..frames {
+            if sender.send(&image, MacAddr::zero()).is_err() {
+                errors += 1;
             }
         }
+        let elapsed = before.elapsed();
+        println!(
+            "{:<8} {:>8.1} fps {:>8.0?} per frame {:>5} errors",
+            name,
+            frames as f64 / elapsed.as_secs_f64(),
+            elapsed / frames.max(1),
+            errors
+        );
+    }
+}
+
+fn list_interfaces() {
+    for interface in pnet::datalink::interfaces() {
+        let mac = interface
+            .mac
+            .map(|mac| mac.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let ips: Vec<String> = interface.ips.iter().map(|ip| ip.to_string()).collect();
+        println!(
+            "{:<16} {:<17} {:<4} {}",
+            interface.name,
+            mac,
+            if interface.is_up() { "up" } else { "down" },
+            ips.join(" ")
+        );
+    }
+}
 
-        if dragon.has_finished() && rng.gen_bool(0.002) {
-            dragon.set_animation(  AnimatedPath::new_random(&mut rng, 16000));
+// Every line on stdin holding a number between 0 and 100 sets the brightness in percent
+fn spawn_brightness_control(brightness: Brightness) {
+    thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            match line.trim().parse::<u8>() {
+                Ok(percent) if percent <= 100 => {
+                    brightness.set_percent(percent);
+                    println!("Brightness set to {}%", percent);
+                }
+                _ => eprintln!("Expected a brightness between 0 and 100, got '{}'", line),
+            }
         }
-        dragon.animate(&mut panel);
+    });
+}
 
-        /*
-        let schwebebahn_offset = ((ts.as_millis() / 50) % 800) as i32;
-        panel.draw_image((schwebebahn_offset - 256) * -1, 138, &schwebebahn[1], 2.0, false, false);
-        panel.draw_image(0, 132, &schwebebahn[0], 2.0, false, false);
-        */
+fn list_receiver_cards(interface_name: &str) {
+    let listener = match ReceiverListener::new(interface_name) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("{}: {}", interface_name, e);
+            return;
+        }
+    };
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let cards = listener.alive_cards(Duration::from_secs(3));
+        println!(
+            "{} receiver card(s) alive, {} bad checksum(s)",
+            cards.len(),
+            listener.bad_checksums()
+        );
+        for card in cards {
+            println!(
+                "  {}: {} replies, last cmd {:#04x}, package {}",
+                card.mac,
+                card.replies,
+                card.last_packet.header.cmd,
+                card.last_packet.header.package_id
+            );
+        }
+    }
+}
 
-        /*
-        let schwebebahn_offset = ((ts.as_millis() / 50) % 800) as i32;
-        panel.draw_image((schwebebahn_offset - 256) * -1, 138, &schwebebahn[1], 2.0, false, false);
-        panel.draw_image(0, 132, &schwebebahn[0], 2.0, false, false);
-        */
+fn receive_frames(endpoint: Endpoint, output: &OutputArgs, snapshot_path: Option<&Path>) {
+    // Either a capture file or a live interface, e.g. the peer of a veth pair
+    let color_format = output.color_order;
+    let geometry = output.geometry();
+    let frames: Box<dyn Iterator<Item = _>> = match endpoint {
+        Endpoint::Capture(path) => {
+            let mut receiver = VirtualReceiver::with_geometry(color_format, geometry);
+            match receiver.decode_pcap(path) {
+                Ok(frames) => Box::new(frames.into_iter()),
+                Err(e) => {
+                    eprintln!("Failed to decode {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+        Endpoint::Interface(interface_name) => {
+            match VirtualReceiver::listen(interface_name, color_format, geometry) {
+                Ok(frames) => Box::new(frames.into_iter()),
+                Err(e) => {
+                    eprintln!("{}: {}", interface_name, e);
+                    return;
+                }
+            }
+        }
+    };
 
-        panel.send(sender.clone(), dst_mac);
+    for (index, frame) in frames.enumerate() {
+        println!(
+            "Frame {}: {} missing, {} duplicate chunk(s)",
+            index,
+            frame.missing_chunks.len(),
+            frame.duplicate_chunks.len()
+        );
+        if let Some(path) = snapshot_path {
+            if let Err(e) = frame.image.save(path) {
+                eprintln!("Failed to save {}: {}", path.display(), e);
+            }
+        }
     }
 }
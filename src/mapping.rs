@@ -0,0 +1,385 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::linsn::FrameGeometry;
+
+const UNMAPPED: u32 = u32::MAX;
+
+#[derive(Debug)]
+pub enum MappingError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingError::Io(e) => write!(f, "failed to read mapping: {}", e),
+            MappingError::Parse(e) => write!(f, "failed to parse mapping: {}", e),
+            MappingError::Invalid(reason) => write!(f, "invalid mapping: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MappingError {}
+
+// Clockwise rotation of a cabinet, given in degrees in the mapping file
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "u32")]
+pub enum Rotation {
+    #[default]
+    None,
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+impl TryFrom<u32> for Rotation {
+    type Error = String;
+
+    fn try_from(degrees: u32) -> Result<Self, Self::Error> {
+        match degrees {
+            0 => Ok(Rotation::None),
+            90 => Ok(Rotation::Cw90),
+            180 => Ok(Rotation::Cw180),
+            270 => Ok(Rotation::Cw270),
+            _ => Err(format!("unsupported rotation of {} degrees", degrees)),
+        }
+    }
+}
+
+// One cabinet of the wall. (x, y, width, height) is the part of the logical
+// canvas it shows, (output_x, output_y) the top-left corner of the cabinet in
+// the sender-card canvas below the row offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cabinet {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub output_x: u32,
+    pub output_y: u32,
+    #[serde(default)]
+    pub rotation: Rotation,
+    #[serde(default)]
+    pub mirror_x: bool,
+    #[serde(default)]
+    pub mirror_y: bool,
+}
+
+impl Cabinet {
+    // Size the cabinet occupies in the sender-card canvas
+    fn output_size(&self) -> (u32, u32) {
+        match self.rotation {
+            Rotation::None | Rotation::Cw180 => (self.width, self.height),
+            Rotation::Cw90 | Rotation::Cw270 => (self.height, self.width),
+        }
+    }
+
+    // Maps a position inside the cabinet's logical region to its output position
+    fn transform(&self, u: u32, v: u32) -> (u32, u32) {
+        let (w, h) = (self.width, self.height);
+        let u = if self.mirror_x { w - 1 - u } else { u };
+        let v = if self.mirror_y { h - 1 - v } else { v };
+        match self.rotation {
+            Rotation::None => (u, v),
+            Rotation::Cw90 => (h - 1 - v, u),
+            Rotation::Cw180 => (w - 1 - u, h - 1 - v),
+            Rotation::Cw270 => (v, w - 1 - u),
+        }
+    }
+}
+
+// Mapping files are TOML. The canvas size defaults to the bounding box of all
+// cabinets:
+//
+//     width = 384
+//     height = 192
+//
+//     [[cabinet]]
+//     x = 0
+//     y = 0
+//     width = 192
+//     height = 192
+//     output_x = 0
+//     output_y = 0
+//     rotation = 90
+//     mirror_x = false
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappingFile {
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(rename = "cabinet")]
+    cabinets: Vec<Cabinet>,
+}
+
+// Lookup table from the logical canvas a scene draws on to the sender-card canvas
+#[derive(Debug, Clone)]
+pub struct PixelMap {
+    width: u32,
+    height: u32,
+    indices: Vec<u32>,
+}
+
+impl PixelMap {
+    // A single unrotated cabinet in the top-left corner of the sender-card canvas
+    // Whatever does not fit onto the sender-card canvas is simply not shown
+    pub fn identity(width: u32, height: u32, geometry: FrameGeometry) -> Self {
+        let mut map = PixelMap {
+            width,
+            height,
+            indices: vec![UNMAPPED; width as usize * height as usize],
+        };
+        for y in 0..height {
+            for x in 0..width {
+                if let Some(index) = geometry.index(x, y) {
+                    map.indices[(y * width + x) as usize] = index as u32;
+                }
+            }
+        }
+        map
+    }
+
+    pub fn from_cabinets(
+        width: u32,
+        height: u32,
+        cabinets: &[Cabinet],
+        geometry: FrameGeometry,
+    ) -> Result<Self, MappingError> {
+        let mut map = PixelMap {
+            width,
+            height,
+            indices: vec![UNMAPPED; width as usize * height as usize],
+        };
+
+        for (number, cabinet) in cabinets.iter().enumerate() {
+            if cabinet.width == 0 || cabinet.height == 0 {
+                return Err(MappingError::Invalid(format!(
+                    "cabinet {} is empty",
+                    number
+                )));
+            }
+            let inside = cabinet.x.checked_add(cabinet.width).is_some_and(|right| right <= width)
+                && cabinet.y.checked_add(cabinet.height).is_some_and(|bottom| bottom <= height);
+            if !inside {
+                return Err(MappingError::Invalid(format!(
+                    "cabinet {} lies outside the {}x{} canvas",
+                    number, width, height
+                )));
+            }
+            if !cabinet_fits(cabinet, geometry) {
+                return Err(MappingError::Invalid(format!(
+                    "cabinet {} does not fit into the {}x{} sender canvas",
+                    number, geometry.width, geometry.height
+                )));
+            }
+
+            for v in 0..cabinet.height {
+                for u in 0..cabinet.width {
+                    let logical = ((cabinet.y + v) * width + cabinet.x + u) as usize;
+                    if map.indices[logical] != UNMAPPED {
+                        return Err(MappingError::Invalid(format!(
+                            "cabinet {} overlaps another cabinet at {}/{}",
+                            number,
+                            cabinet.x + u,
+                            cabinet.y + v
+                        )));
+                    }
+                    let (out_x, out_y) = cabinet.transform(u, v);
+                    let index = geometry
+                        .index(cabinet.output_x + out_x, cabinet.output_y + out_y)
+                        .unwrap();
+                    map.indices[logical] = index as u32;
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, geometry: FrameGeometry) -> Result<Self, MappingError> {
+        let content = fs::read_to_string(path).map_err(MappingError::Io)?;
+        PixelMap::parse(&content, geometry)
+    }
+
+    pub fn parse(content: &str, geometry: FrameGeometry) -> Result<Self, MappingError> {
+        let file: MappingFile = toml::from_str(content).map_err(MappingError::Parse)?;
+        let width = match file.width {
+            Some(width) => width,
+            None => max_extent(&file.cabinets, |c| c.x.checked_add(c.width))?,
+        };
+        let height = match file.height {
+            Some(height) => height,
+            None => max_extent(&file.cabinets, |c| c.y.checked_add(c.height))?,
+        };
+        PixelMap::from_cabinets(width, height, &file.cabinets, geometry)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // Index into the sender-card canvas, None for pixels no cabinet shows
+    pub fn index(&self, x: u32, y: u32) -> Option<usize> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        match self.indices[(y * self.width + x) as usize] {
+            UNMAPPED => None,
+            index => Some(index as usize),
+        }
+    }
+}
+
+// Canvas size needed to show every cabinet, without a file given width or height
+fn max_extent(
+    cabinets: &[Cabinet],
+    end: impl Fn(&Cabinet) -> Option<u32>,
+) -> Result<u32, MappingError> {
+    cabinets.iter().enumerate().try_fold(0, |extent, (number, cabinet)| {
+        end(cabinet).map(|end| extent.max(end)).ok_or_else(|| {
+            MappingError::Invalid(format!("cabinet {} lies outside the canvas", number))
+        })
+    })
+}
+
+fn cabinet_fits(cabinet: &Cabinet, geometry: FrameGeometry) -> bool {
+    let (out_width, out_height) = cabinet.output_size();
+    // Last output pixel, None if the position overflows
+    let corner = || {
+        Some((
+            cabinet.output_x.checked_add(out_width.checked_sub(1)?)?,
+            cabinet.output_y.checked_add(out_height.checked_sub(1)?)?,
+        ))
+    };
+    corner()
+        .and_then(|(x, y)| geometry.index(x, y))
+        .is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cabinet(rotation: Rotation, mirror_x: bool) -> Cabinet {
+        Cabinet {
+            x: 0,
+            y: 0,
+            width: 3,
+            height: 2,
+            output_x: 0,
+            output_y: 0,
+            rotation,
+            mirror_x,
+            mirror_y: false,
+        }
+    }
+
+    #[test]
+    fn test_identity_matches_geometry() {
+        let geometry = FrameGeometry::default();
+        let map = PixelMap::identity(192, 192, geometry);
+        assert_eq!(map.index(0, 0), geometry.index(0, 0));
+        assert_eq!(map.index(191, 191), geometry.index(191, 191));
+        assert_eq!(map.index(192, 0), None);
+    }
+
+    #[test]
+    fn test_rotations() {
+        let geometry = FrameGeometry::new(8, 8, 0);
+        // Logical top-right pixel (2, 0) of a 3x2 cabinet
+        let expected = [
+            (Rotation::None, (2, 0)),
+            (Rotation::Cw90, (1, 2)),
+            (Rotation::Cw180, (0, 1)),
+            (Rotation::Cw270, (0, 0)),
+        ];
+        for (rotation, (x, y)) in expected {
+            let map = PixelMap::from_cabinets(3, 2, &[cabinet(rotation, false)], geometry).unwrap();
+            assert_eq!(map.index(2, 0), geometry.index(x, y), "{:?}", rotation);
+        }
+
+        let map = PixelMap::from_cabinets(3, 2, &[cabinet(Rotation::None, true)], geometry).unwrap();
+        assert_eq!(map.index(2, 0), geometry.index(0, 0));
+    }
+
+    #[test]
+    fn test_parse_two_cabinets() {
+        let geometry = FrameGeometry::default();
+        let map = PixelMap::parse(
+            r#"
+            [[cabinet]]
+            x = 0
+            y = 0
+            width = 192
+            height = 192
+            output_x = 0
+            output_y = 0
+
+            [[cabinet]]
+            x = 192
+            y = 0
+            width = 192
+            height = 192
+            output_x = 192
+            output_y = 0
+            rotation = 180
+            "#,
+            geometry,
+        )
+        .unwrap();
+
+        assert_eq!((map.width(), map.height()), (384, 192));
+        assert_eq!(map.index(10, 20), geometry.index(10, 20));
+        assert_eq!(map.index(192, 0), geometry.index(383, 191));
+    }
+
+    #[test]
+    fn test_rejects_invalid_layouts() {
+        let geometry = FrameGeometry::new(8, 8, 1);
+        let overlapping = [cabinet(Rotation::None, false), cabinet(Rotation::None, false)];
+        assert!(PixelMap::from_cabinets(3, 2, &overlapping, geometry).is_err());
+
+        let mut outside = cabinet(Rotation::None, false);
+        outside.output_y = 6;
+        assert!(PixelMap::from_cabinets(3, 2, &[outside], geometry).is_err());
+
+        // Positions close to u32::MAX must not overflow
+        let mut far_away = cabinet(Rotation::None, false);
+        far_away.x = u32::MAX;
+        assert!(matches!(
+            PixelMap::from_cabinets(3, 2, &[far_away], geometry),
+            Err(MappingError::Invalid(_))
+        ));
+        far_away.x = 0;
+        far_away.output_x = u32::MAX;
+        assert!(matches!(
+            PixelMap::from_cabinets(3, 2, &[far_away], geometry),
+            Err(MappingError::Invalid(_))
+        ));
+        assert!(matches!(
+            PixelMap::parse(
+                "[[cabinet]]\nx=4294967295\ny=0\nwidth=1\nheight=1\noutput_x=0\noutput_y=0",
+                geometry
+            ),
+            Err(MappingError::Invalid(_))
+        ));
+
+        assert!(PixelMap::parse("[[cabinet]]\nx = 0", geometry).is_err());
+        assert!(PixelMap::parse(
+            "[[cabinet]]\nx=0\ny=0\nwidth=1\nheight=1\noutput_x=0\noutput_y=0\nrotation=45",
+            geometry
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC_MICROS: u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NANOS: u32 = 0xA1B2_3C4D;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+// Largest snaplen libpcap writes, anything above is a corrupt file
+const PCAP_MAX_SNAPLEN: u32 = 262144;
+
+// Minimal writer for the classic libpcap format with microsecond timestamps
+pub struct PcapWriter<W: Write> {
+    out: W,
+}
+
+impl PcapWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        PcapWriter::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(&PCAP_MAGIC_MICROS.to_le_bytes())?;
+        out.write_all(&PCAP_VERSION_MAJOR.to_le_bytes())?;
+        out.write_all(&PCAP_VERSION_MINOR.to_le_bytes())?;
+        out.write_all(&0i32.to_le_bytes())?; // thiszone
+        out.write_all(&0u32.to_le_bytes())?; // sigfigs
+        out.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        out.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        Ok(PcapWriter { out })
+    }
+
+    pub fn write_frame(&mut self, timestamp: SystemTime, frame: &[u8]) -> io::Result<()> {
+        let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let captured = frame.len().min(PCAP_SNAPLEN as usize);
+
+        self.out
+            .write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        self.out
+            .write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+        self.out.write_all(&(captured as u32).to_le_bytes())?;
+        self.out.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(&frame[..captured])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PcapRecord {
+    // Capture time relative to the unix epoch
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+// Reads classic libpcap files in either byte order with micro- or nanosecond timestamps
+pub struct PcapReader<R: Read> {
+    input: R,
+    big_endian: bool,
+    nanosecond: bool,
+    snaplen: u32,
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        PcapReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        input.read_exact(&mut header)?;
+
+        let magic = [header[0], header[1], header[2], header[3]];
+        let (big_endian, nanosecond) = if magic == PCAP_MAGIC_MICROS.to_le_bytes() {
+            (false, false)
+        } else if magic == PCAP_MAGIC_MICROS.to_be_bytes() {
+            (true, false)
+        } else if magic == PCAP_MAGIC_NANOS.to_le_bytes() {
+            (false, true)
+        } else if magic == PCAP_MAGIC_NANOS.to_be_bytes() {
+            (true, true)
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a pcap file (pcapng is not supported)",
+            ));
+        };
+
+        let mut reader = PcapReader {
+            input,
+            big_endian,
+            nanosecond,
+            snaplen: PCAP_MAX_SNAPLEN,
+        };
+        let snaplen = reader.u32_from(&header[16..20]);
+        if snaplen != 0 {
+            reader.snaplen = snaplen.min(PCAP_MAX_SNAPLEN);
+        }
+        let link_type = reader.u32_from(&header[20..24]);
+        if link_type != LINKTYPE_ETHERNET {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported link type {}", link_type),
+            ));
+        }
+        Ok(reader)
+    }
+
+    fn u32_from(&self, bytes: &[u8]) -> u32 {
+        let bytes: [u8; 4] = bytes.try_into().unwrap();
+        match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    }
+
+    pub fn next_record(&mut self) -> io::Result<Option<PcapRecord>> {
+        let mut header = [0u8; 16];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let seconds = self.u32_from(&header[0..4]) as u64;
+        let fraction = self.u32_from(&header[4..8]);
+        let captured = self.u32_from(&header[8..12]);
+        if captured > self.snaplen {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record of {} bytes exceeds the snaplen of {}", captured, self.snaplen),
+            ));
+        }
+
+        let mut data = vec![0u8; captured as usize];
+        self.input.read_exact(&mut data)?;
+
+        let timestamp = match self.nanosecond {
+            true => Duration::new(seconds, fraction),
+            false => Duration::new(seconds, 0) + Duration::from_micros(fraction as u64),
+        };
+        Ok(Some(PcapRecord { timestamp, data }))
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = io::Result<PcapRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read_back() {
+        let mut file = vec![];
+        let first = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let second = first + Duration::from_millis(16);
+        {
+            let mut writer = PcapWriter::new(&mut file).unwrap();
+            writer.write_frame(first, &[0xAA; 60]).unwrap();
+            writer.write_frame(second, &[0x55; 1486]).unwrap();
+            writer.flush().unwrap();
+        }
+
+        let records: Vec<PcapRecord> = PcapReader::new(file.as_slice())
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].timestamp,
+            first.duration_since(UNIX_EPOCH).unwrap()
+        );
+        assert_eq!(records[0].data, vec![0xAA; 60]);
+        assert_eq!(
+            records[1].timestamp - records[0].timestamp,
+            Duration::from_millis(16)
+        );
+        assert_eq!(records[1].data, vec![0x55; 1486]);
+    }
+
+    #[test]
+    fn test_rejects_oversized_records() {
+        let mut file = vec![];
+        PcapWriter::new(&mut file).unwrap();
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        let error = PcapReader::new(file.as_slice()).unwrap().next_record().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
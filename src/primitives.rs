@@ -1,9 +1,9 @@
-use std::sync::{mpsc::Sender, Arc};
+use std::sync::{Arc, Mutex};
 
-use image::{imageops::{flip_horizontal, flip_vertical, resize}, DynamicImage, ImageBuffer, Rgb, Rgba};
+use image::{imageops::{flip_horizontal, flip_vertical, resize}, DynamicImage, Rgb, Rgba};
 use pnet::util::MacAddr;
 
-use crate::{linsn::{LINSN_FRAME_HEIGHT, LINSN_FRAME_WIDTH}, socket::LinsnSocket, sprite::AnimatedSprite};
+use crate::{encoder::Damage, linsn::FrameGeometry, mapping::PixelMap, scheduler::{CommittedFrame, OutputConfig, OutputScheduler, OutputStats, SharedFrame}, socket::{LinsnSocket, SenderError}};
 
 pub struct Panel {
     pub width: usize, 
@@ -11,30 +11,42 @@ pub struct Panel {
     image_buffer_active: Vec<Rgb<u8>>,
     image_buffer_inactive: Vec<Rgb<u8>>,
     pub double_buffering: bool,
-    sprites: Vec<AnimatedSprite>,
     flip: bool,
+    mapping: PixelMap,
+    front_buffer: SharedFrame,
+    output: Option<OutputScheduler>,
+    // Chunks changed since the last send or present
+    damage: Damage,
 }
 
 impl Panel {
-pub fn new(width : usize, height: usize, double_buffering: bool, flip: bool) -> Self {
+pub fn with_geometry(width : usize, height: usize, geometry: FrameGeometry, double_buffering: bool, flip: bool) -> Self {
+    let mapping = PixelMap::identity(width as u32, height as u32, geometry);
+    Panel::with_mapping(mapping, geometry, double_buffering, flip)
+}
+
+// The panel size is the logical canvas of the mapping
+pub fn with_mapping(mapping: PixelMap, geometry: FrameGeometry, double_buffering: bool, flip: bool) -> Self {
     let image_buffer_active = vec![
         Rgb([0x69,0x20,0x69]);
-        LINSN_FRAME_WIDTH as usize
-            * LINSN_FRAME_HEIGHT as usize
+        geometry.pixel_count()
     ];
     let image_buffer_inactive = vec![
         Rgb([0x00,0x00,0x00]);
-        LINSN_FRAME_WIDTH as usize
-            * LINSN_FRAME_HEIGHT as usize
+        geometry.pixel_count()
     ];
+    let front_buffer = Arc::new(Mutex::new(CommittedFrame::new(image_buffer_active.clone())));
     Panel {
-        width,
-        height,
+        width: mapping.width() as usize,
+        height: mapping.height() as usize,
         image_buffer_active,
         image_buffer_inactive,
         double_buffering,
-        sprites: vec![],
-        flip
+        flip,
+        mapping,
+        front_buffer,
+        output: None,
+        damage: Damage::full(),
     }
 }
 
@@ -47,11 +59,12 @@ pub fn clear(&mut self) {
 }
 
 pub fn set_pixel(&mut self,dest_x: i32, dest_y: i32, pixel: Rgba<u8>) {
-    if dest_x < 0 || dest_y < 0 || dest_x >= self.width as i32 || dest_y > self.height as i32 {
+    if dest_x < 0 || dest_y < 0 || dest_x >= self.width as i32 || dest_y >= self.height as i32 {
         return;
     }
-    let target_width = LINSN_FRAME_WIDTH as usize;
-    let stride = (dest_y + 1) as usize * target_width ;
+    let Some(index) = self.mapping.index(dest_x as u32, dest_y as u32) else {
+        return;
+    };
 
     let buffer = match self.double_buffering {
         true => &mut self.image_buffer_inactive,
@@ -64,15 +77,21 @@ pub fn set_pixel(&mut self,dest_x: i32, dest_y: i32, pixel: Rgba<u8>) {
         return
     }
 
-    if alpha != 0xFF {
+    let new = if alpha != 0xFF {
         let factor = alpha as f32 / 0xFF as f32;
-        let old = buffer[stride + dest_x as usize];
+        let old = buffer[index];
         let r = ((old[0] as f32 * (1.0-factor)) + (pixel[0] as f32 * factor)) as u8;
         let g = ((old[1] as f32 * (1.0-factor)) + (pixel[1] as f32 * factor)) as u8;
         let b = ((old[2] as f32 * (1.0-factor)) + (pixel[2] as f32 * factor)) as u8;
-        buffer[stride + dest_x as usize]= Rgb([r,g,b]);
+        Rgb([r,g,b])
     } else {
-        buffer[stride + dest_x as usize] = Rgb([pixel[0], pixel[1], pixel[2]]);
+        Rgb([pixel[0], pixel[1], pixel[2]])
+    };
+
+    // Redrawing the same content, e.g. after clear(), does not damage anything
+    if buffer[index] != new {
+        buffer[index] = new;
+        self.damage.mark_pixel(index);
     }
 }
 
@@ -80,7 +99,7 @@ pub fn draw_image(&mut self, dest_x: i32, dest_y: i32, image: &DynamicImage, sca
 
     let nwidth = (image.width() as f32 *scale) as u32;
     let nheight = (image.height()as f32 *scale) as u32;
-    if nwidth <= 0 || nheight <= 0 {
+    if nwidth == 0 || nheight == 0 {
         return;
     }
     let mut image = resize(
@@ -99,15 +118,131 @@ pub fn draw_image(&mut self, dest_x: i32, dest_y: i32, image: &DynamicImage, sca
 
     for row in 0..image.height() {
         for i in 0..image.width() {
-            self.set_pixel(dest_x + i as i32, dest_y+row as i32, image[(i as u32, row as u32)]);
+            self.set_pixel(dest_x + i as i32, dest_y+row as i32, image[(i, row)]);
         }
     }
 }
-pub fn send(&mut self, sender: Arc<dyn LinsnSocket>, dst_mac: MacAddr) {
-    if self.double_buffering {
-        panic!("not implemented yet");
+// Without double buffering the frame is sent right away. Otherwise it is handed
+// to the output scheduler, which is started on the first call.
+pub fn send(&mut self, sender: Arc<dyn LinsnSocket + Send + Sync>, dst_mac: MacAddr) -> Result<(), SenderError> {
+    if !self.double_buffering {
+        sender.send_damaged(&self.image_buffer_active, dst_mac, &self.damage)?;
+        self.damage = Damage::default();
+        return Ok(());
+    }
+
+    if self.output.is_none() {
+        self.start_output(sender, dst_mac, OutputConfig::default());
+    }
+    self.present();
+    Ok(())
+}
+
+// Keeps transmitting the front buffer at the refresh rate until the panel is
+// dropped, independent of how long rendering the next frame takes
+pub fn start_output(&mut self, sender: Arc<dyn LinsnSocket + Send + Sync>, dst_mac: MacAddr, config: OutputConfig) {
+    // Stop a previous scheduler before starting the new one
+    self.output = None;
+    // A new sender has nothing cached yet
+    self.front_buffer.lock().expect("Mutex Poisend").damage = Damage::full();
+    self.output = Some(OutputScheduler::start(Arc::clone(&self.front_buffer), sender, dst_mac, config));
+}
+
+pub fn output_stats(&self) -> Option<OutputStats> {
+    self.output.as_ref().map(|output| output.stats())
+}
+
+// Publishes the back buffer to the output scheduler. The swap happens under the
+// lock, so the scheduler only ever sees complete frames.
+pub fn present(&mut self) {
+    if !self.double_buffering {
+        return;
     }
 
-    sender.send(&self.image_buffer_active, dst_mac);
+    let presented = Arc::new(std::mem::take(&mut self.image_buffer_inactive));
+    let previous = {
+        let mut front = self.front_buffer.lock().expect("Mutex Poisend");
+        // Frames the scheduler did not pick up yet still count as changed
+        front.damage.merge(&self.damage.take());
+        std::mem::replace(&mut front.pixels, Arc::clone(&presented))
+    };
+
+    // The old front buffer can be reused unless it is still being sent
+    let mut back = Arc::try_unwrap(previous).unwrap_or_else(|shared| (*shared).clone());
+    // Drawing continues on top of the frame just presented
+    back.copy_from_slice(&presented);
+    self.image_buffer_inactive = back;
+}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    use crate::linsn::ColorFormat;
+    use crate::socket::{ChannelSender, SenderSettings};
+    use crate::virtual_receiver::{DecodedFrame, VirtualReceiver};
+
+    // The output scheduler keeps repeating the front buffer, so older frames may
+    // still be queued in the channel
+    fn wait_for_pixel(receiver: &mut VirtualReceiver, rx: &mpsc::Receiver<Vec<u8>>, x: u32, y: u32, pixel: Rgb<u8>) -> DecodedFrame {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            let frame = receiver.decode_channel(rx).expect("Output scheduler stopped");
+            if frame.image[(x, y)] == pixel {
+                return frame;
+            }
+        }
+        panic!("Presented frame never sent");
+    }
+
+    #[test]
+    fn test_double_buffered_output() {
+        let geometry = FrameGeometry::new(16, 16, 0);
+        let (tx, rx) = mpsc::channel();
+        let sender = Arc::new(ChannelSender::new(tx, SenderSettings::default().with_geometry(geometry)));
+        let mut receiver = VirtualReceiver::with_geometry(ColorFormat::default(), geometry);
+
+        let mut panel = Panel::with_geometry(4, 4, geometry, true, false);
+        panel.set_pixel(1, 1, Rgba([0xFF, 0, 0, 0xFF]));
+        panel.send(sender, MacAddr::zero()).unwrap();
+        let presented = wait_for_pixel(&mut receiver, &rx, 1, 1, Rgb([0xFF, 0, 0]));
+        assert!(presented.is_complete());
+
+        // The back buffer starts out as a copy of the presented frame
+        panel.set_pixel(2, 2, Rgba([0, 0xFF, 0, 0xFF]));
+        panel.present();
+        let next = wait_for_pixel(&mut receiver, &rx, 2, 2, Rgb([0, 0xFF, 0]));
+        assert_eq!(next.image[(1, 1)], Rgb([0xFF, 0, 0]));
+    }
+
+    #[test]
+    fn test_damage_tracking() {
+        let geometry = FrameGeometry::new(64, 20, 0);
+        let (tx, rx) = mpsc::channel();
+        let sender: Arc<dyn LinsnSocket + Send + Sync> = Arc::new(ChannelSender::new(tx, SenderSettings::default().with_geometry(geometry)));
+        let mut receiver = VirtualReceiver::with_geometry(ColorFormat::default(), geometry);
+
+        let mut panel = Panel::with_geometry(64, 20, geometry, false, false);
+        panel.clear();
+        panel.send(sender.clone(), MacAddr::zero()).unwrap();
+        assert!(panel.damage.is_empty());
+
+        // Redrawing identical content leaves everything clean
+        panel.clear();
+        assert!(panel.damage.is_empty());
+
+        // Pixel 480 starts the second chunk
+        panel.set_pixel(32, 7, Rgba([0, 0, 0xFF, 0xFF]));
+        assert!(!panel.damage.is_dirty(0));
+        assert!(panel.damage.is_dirty(1));
+        panel.send(sender, MacAddr::zero()).unwrap();
+
+        receiver.decode_channel(&rx).unwrap();
+        let frame = receiver.decode_channel(&rx).unwrap();
+        assert_eq!(frame.image[(32, 7)], Rgb([0, 0, 0xFF]));
+        assert_eq!(frame.image[(0, 0)], Rgb([0, 0, 0]));
+    }
 }
-}
\ No newline at end of file
@@ -0,0 +1,129 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::scene::Scene;
+
+// Checking a handful of files twice per second costs next to nothing
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Size and modification time of a file, or of every entry of a directory
+type Stamp = Vec<(PathBuf, u64, Option<SystemTime>)>;
+
+fn stamp(path: &Path) -> Stamp {
+    let mut stamp = vec![];
+    let Ok(metadata) = fs::metadata(path) else {
+        return stamp;
+    };
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+            if let Ok(metadata) = entry.metadata() {
+                stamp.push((entry.path(), metadata.len(), metadata.modified().ok()));
+            }
+        }
+        stamp.sort();
+    } else {
+        stamp.push((path.to_path_buf(), metadata.len(), metadata.modified().ok()));
+    }
+    stamp
+}
+
+// Polls files and directories for changes. Editors save files in different
+// ways, comparing sizes and modification times catches all of them and does
+// not depend on inotify.
+pub struct FileWatcher {
+    watched: Vec<(PathBuf, Stamp)>,
+}
+
+impl FileWatcher {
+    pub fn new<I: IntoIterator<Item = PathBuf>>(paths: I) -> Self {
+        let mut watcher = FileWatcher { watched: vec![] };
+        watcher.watch(paths);
+        watcher
+    }
+
+    // Starts watching paths that are not watched yet
+    pub fn watch<I: IntoIterator<Item = PathBuf>>(&mut self, paths: I) {
+        for path in paths {
+            if !self.watched.iter().any(|(watched, _)| *watched == path) {
+                let stamp = stamp(&path);
+                self.watched.push((path, stamp));
+            }
+        }
+    }
+
+    // Paths that changed since the previous poll
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        let mut changed = vec![];
+        for (path, last) in &mut self.watched {
+            let current = stamp(path);
+            if current != *last {
+                *last = current;
+                changed.push(path.clone());
+            }
+        }
+        changed
+    }
+}
+
+// Reloads a running scene whenever its file or one of its assets changes
+pub struct SceneReloader {
+    watcher: FileWatcher,
+    last_poll: Instant,
+}
+
+impl SceneReloader {
+    pub fn new(scene: &Scene) -> Self {
+        SceneReloader {
+            watcher: FileWatcher::new(scene.sources()),
+            last_poll: Instant::now(),
+        }
+    }
+
+    // Cheap enough to call every frame. A broken scene or asset is logged and the
+    // scene keeps drawing what it loaded before.
+    pub fn poll(&mut self, scene: &mut Scene) {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return;
+        }
+        self.last_poll = Instant::now();
+
+        let changed = self.watcher.poll();
+        if changed.is_empty() {
+            return;
+        }
+        match scene.reload(&changed) {
+            Ok(layers) => println!("Reloaded {} layer(s)", layers),
+            Err(e) => eprintln!("Keeping the previous version: {}", e),
+        }
+        // An edited scene file may use other assets now
+        self.watcher.watch(scene.sources());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_detects_changes() {
+        let dir = TempDir::new("watch");
+        let file = dir.join("scene.toml");
+        fs::write(&file, "a").unwrap();
+
+        let mut watcher = FileWatcher::new([file.clone(), dir.to_path_buf()]);
+        assert!(watcher.poll().is_empty());
+
+        fs::write(&file, "ab").unwrap();
+        assert_eq!(watcher.poll(), vec![file.clone(), dir.to_path_buf()]);
+        assert!(watcher.poll().is_empty());
+
+        // New files show up as a change of the directory
+        fs::write(dir.join("frame.png"), "").unwrap();
+        assert_eq!(watcher.poll(), vec![dir.to_path_buf()]);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(watcher.poll(), vec![file, dir.to_path_buf()]);
+    }
+}
@@ -0,0 +1,156 @@
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::linsn::ETHERNET_TYPE_SENDER;
+use crate::pcap::PcapReader;
+use crate::socket::BatchedSocketSender;
+
+// Upper bound for frames handed to a single sendmmsg call
+const MAX_BATCH_SIZE: usize = 1024;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReplayStats {
+    pub frames_sent: usize,
+    pub frames_skipped: usize,
+    pub duration: Duration,
+}
+
+// Resends all 0xAA55 frames of a capture byte for byte. The original inter-frame
+// timing is scaled by `speed`, pass f64::INFINITY to replay as fast as possible.
+pub fn replay_pcap<P: AsRef<Path>>(
+    path: P,
+    sender: &BatchedSocketSender,
+    speed: f64,
+) -> io::Result<ReplayStats> {
+    let mut pacing = Pacing::new(speed)?;
+    let reader = PcapReader::open(path)?;
+    let mut stats = ReplayStats::default();
+    let mut batch: Vec<Vec<u8>> = Vec::with_capacity(MAX_BATCH_SIZE);
+    let start = Instant::now();
+
+    for record in reader {
+        let record = record?;
+        if !is_linsn_frame(&record.data) {
+            stats.frames_skipped += 1;
+            continue;
+        }
+
+        let due = pacing.due(record.timestamp);
+        if starts_new_batch(due, start.elapsed(), batch.len()) {
+            stats.frames_sent += flush(sender, &mut batch)?;
+            thread::sleep(due.saturating_sub(start.elapsed()));
+        }
+        batch.push(record.data);
+    }
+    stats.frames_sent += flush(sender, &mut batch)?;
+    stats.duration = start.elapsed();
+
+    Ok(stats)
+}
+
+// When the frames of a capture are due, relative to the start of the replay
+struct Pacing {
+    speed: f64,
+    first_timestamp: Option<Duration>,
+}
+
+impl Pacing {
+    fn new(speed: f64) -> io::Result<Self> {
+        if speed.is_nan() || speed <= 0.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "speed factor must be positive",
+            ));
+        }
+        Ok(Pacing {
+            speed,
+            first_timestamp: None,
+        })
+    }
+
+    // The first frame is due right away, the others keep their distance to it
+    fn due(&mut self, timestamp: Duration) -> Duration {
+        let first = *self.first_timestamp.get_or_insert(timestamp);
+        timestamp.saturating_sub(first).div_f64(self.speed)
+    }
+}
+
+// Everything that is already due goes out in one batch
+fn starts_new_batch(due: Duration, elapsed: Duration, batch_len: usize) -> bool {
+    due > elapsed || batch_len == MAX_BATCH_SIZE
+}
+
+fn is_linsn_frame(frame: &[u8]) -> bool {
+    frame.len() > 14 && frame[12..14] == ETHERNET_TYPE_SENDER.to_be_bytes()
+}
+
+fn flush(sender: &BatchedSocketSender, batch: &mut Vec<Vec<u8>>) -> io::Result<usize> {
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    sender.send_frames(batch).map_err(io::Error::other)?;
+    let sent = batch.len();
+    batch.clear();
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcap::PcapWriter;
+    use std::time::UNIX_EPOCH;
+
+    fn frame(ether_type: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; 60];
+        frame[12..14].copy_from_slice(&ether_type.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_pacing_follows_capture() {
+        let mut file = vec![];
+        {
+            let mut writer = PcapWriter::new(&mut file).unwrap();
+            let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            for (offset_ms, ether_type) in [
+                (0, ETHERNET_TYPE_SENDER),
+                (16, ETHERNET_TYPE_SENDER),
+                (20, 0x0800),
+                (50, ETHERNET_TYPE_SENDER),
+            ] {
+                writer
+                    .write_frame(start + Duration::from_millis(offset_ms), &frame(ether_type))
+                    .unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        let mut pacing = Pacing::new(2.0).unwrap();
+        let due: Vec<Duration> = PcapReader::new(file.as_slice())
+            .unwrap()
+            .map(Result::unwrap)
+            .filter(|record| is_linsn_frame(&record.data))
+            .map(|record| pacing.due(record.timestamp))
+            .collect();
+        assert_eq!(due, [0, 8, 25].map(Duration::from_millis));
+
+        // As fast as possible sends everything at once
+        let mut pacing = Pacing::new(f64::INFINITY).unwrap();
+        assert_eq!(pacing.due(Duration::from_secs(5)), Duration::ZERO);
+        assert_eq!(pacing.due(Duration::from_secs(9)), Duration::ZERO);
+
+        for speed in [0.0, -1.0, f64::NAN] {
+            assert!(Pacing::new(speed).is_err());
+        }
+    }
+
+    #[test]
+    fn test_batches() {
+        let ms = Duration::from_millis;
+        assert!(!starts_new_batch(ms(5), ms(10), 3));
+        assert!(starts_new_batch(ms(15), ms(10), 3));
+        assert!(starts_new_batch(ms(5), ms(10), MAX_BATCH_SIZE));
+    }
+}
@@ -0,0 +1,646 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use image::DynamicImage;
+use rand::rngs::ThreadRng;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::primitives::Panel;
+use crate::animated_image::{is_animated_image, load_animated_image};
+use crate::aseprite::{is_sheet, load_sheet};
+use crate::sprite::{
+    load_frame_directory, load_image_directory, AnimatedPath, AnimatedSprite, AssetError, Easing, LoopMode,
+    PathCurve, PathPlayback, PathPoint,
+};
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Asset(AssetError),
+    Invalid(String),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io(e) => write!(f, "failed to read scene: {}", e),
+            SceneError::Parse(e) => write!(f, "failed to parse scene: {}", e),
+            SceneError::Asset(e) => write!(f, "{}", e),
+            SceneError::Invalid(reason) => write!(f, "invalid scene: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl From<AssetError> for SceneError {
+    fn from(e: AssetError) -> Self {
+        SceneError::Asset(e)
+    }
+}
+
+// Scene files are TOML. Layers are drawn in the order they are listed, asset
+// paths are relative to the scene file:
+//
+//     [[layer]]
+//     type = "tiles"
+//     frames = "../assets/sky"   # or image = "../assets/grass.png"
+//     y = 0
+//     count = 8
+//     spacing = 32
+//     scale = 2.0
+//     scroll_interval_ms = 500   # moves one pixel to the left every 500ms
+//     random = true
+//
+//     [[layer]]
+//     type = "sprite"
+//     frames = "../assets/dragon"
+//     framerate = 2.5
+//     loop_mode = "ping_pong"
+//     scale = 2.0
+//     path = { kind = "random", duration_ms = 16000, easing = "ease_in_out", restart_chance = 0.002 }
+//
+//     [[layer]]
+//     type = "sprite"
+//     frames = "../assets/dragon"
+//     framerate = 2.5
+//     [layer.path]
+//     kind = "points"
+//     curve = "catmull_rom"       # or "linear", "bezier"
+//     playback = "ping_pong"      # or "once", "loop"
+//     points = [
+//         { x = 0, y = 40 },
+//         { x = 80, y = 10, duration_ms = 3000, easing = "ease_out" },
+//         { x = 160, y = 40, duration_ms = 3000, easing = "bounce" },
+//     ]
+//
+//     [[layer]]
+//     type = "sprite"
+//     frames = "../assets/bird.json"   # Aseprite sheet or animated GIF, APNG or WebP,
+//                                      # frames carry their own durations
+//     animation = "land"               # one of the tags of the sheet
+//     then = "idle"                    # played once "land" is finished
+//     positions = [[16, 40]]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    #[serde(rename = "layer")]
+    layers: Vec<LayerConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum LayerConfig {
+    // A row of images, optionally scrolling to the left
+    Tiles {
+        image: Option<PathBuf>,
+        frames: Option<PathBuf>,
+        #[serde(default)]
+        x: i32,
+        y: i32,
+        count: usize,
+        spacing: i32,
+        #[serde(default = "default_scale")]
+        scale: f32,
+        // Milliseconds per pixel, the row stands still without it
+        scroll_interval_ms: Option<u64>,
+        // Scroll distance after which the row jumps back, defaults to the spacing
+        scroll_wrap: Option<i32>,
+        // Picks the tiles at random and replaces one whenever the row jumps back
+        #[serde(default)]
+        random: bool,
+    },
+    Sprite {
+        // A directory of PNGs, the JSON file of an Aseprite sheet or an animated
+        // GIF, APNG or WebP file
+        frames: PathBuf,
+        // Only needed if some frames have no duration of their own
+        framerate: Option<f32>,
+        // Tag of an Aseprite sheet, replaces the loop mode with the one of the tag.
+        // The name of a single frame holds that frame.
+        animation: Option<String>,
+        // Tag that follows once the sprite is finished
+        then: Option<String>,
+        // "loop", "ping_pong", "once", "reverse", "random" or { loop_n = 3 }
+        #[serde(default)]
+        loop_mode: LoopMode,
+        #[serde(default = "default_scale")]
+        scale: f32,
+        #[serde(default)]
+        flip: bool,
+        // Every position shows a copy of the sprite, unused with a path
+        #[serde(default = "default_positions")]
+        positions: Vec<(i32, i32)>,
+        path: Option<PathConfig>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+enum PathConfig {
+    // Moves along the points, duration_ms is the time to reach a point
+    Points {
+        points: Vec<PathPointConfig>,
+        #[serde(default)]
+        curve: PathCurve,
+        #[serde(default)]
+        playback: PathPlayback,
+        #[serde(default)]
+        restart_chance: f64,
+    },
+    // Crosses the upper part of the panel at a random height and direction
+    Random {
+        duration_ms: u64,
+        #[serde(default)]
+        easing: Easing,
+        #[serde(default)]
+        restart_chance: f64,
+    },
+}
+
+impl LayerConfig {
+    // Files and directories the layer loads its images from
+    fn sources(&self, base_dir: &Path) -> Vec<PathBuf> {
+        match self {
+            LayerConfig::Tiles { image, frames, .. } => {
+                image.iter().chain(frames).map(|path| base_dir.join(path)).collect()
+            }
+            // The image of a sheet is named inside of it, so its whole directory is watched
+            LayerConfig::Sprite { frames, .. } if is_sheet(frames) => {
+                let sheet = base_dir.join(frames);
+                vec![sheet.parent().map(Path::to_path_buf).unwrap_or(sheet)]
+            }
+            LayerConfig::Sprite { frames, .. } => vec![base_dir.join(frames)],
+        }
+    }
+}
+
+impl PathConfig {
+    // Chance per frame that a finished path starts over
+    fn restart_chance(&self) -> f64 {
+        match self {
+            PathConfig::Points { restart_chance, .. } => *restart_chance,
+            PathConfig::Random { restart_chance, .. } => *restart_chance,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PathPointConfig {
+    x: i32,
+    y: i32,
+    #[serde(default)]
+    duration_ms: u64,
+    #[serde(default)]
+    flip: bool,
+    // Speed curve on the way to this point
+    #[serde(default)]
+    easing: Easing,
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+fn default_positions() -> Vec<(i32, i32)> {
+    vec![(0, 0)]
+}
+
+struct TileLayer {
+    images: Vec<DynamicImage>,
+    x: i32,
+    y: i32,
+    spacing: i32,
+    scale: f32,
+    scroll_interval_ms: Option<u64>,
+    scroll_wrap: i32,
+    random: bool,
+    // Index into images for every tile of the row
+    tiles: Vec<usize>,
+    last_offset: i32,
+}
+
+impl TileLayer {
+    fn draw(&mut self, panel: &mut Panel, elapsed: Duration, rng: &mut ThreadRng) {
+        let offset = match self.scroll_interval_ms {
+            Some(interval) => ((elapsed.as_millis() / interval as u128) % self.scroll_wrap as u128) as i32,
+            None => 0,
+        };
+        // The first tile scrolled out, a new one enters on the right
+        if self.random && offset < self.last_offset {
+            self.tiles.remove(0);
+            self.tiles.push(rng.gen_range(0..self.images.len()));
+        }
+        self.last_offset = offset;
+
+        for (i, tile) in self.tiles.iter().enumerate() {
+            panel.draw_image(
+                self.x + i as i32 * self.spacing - offset,
+                self.y,
+                &self.images[*tile],
+                self.scale,
+                false,
+                false,
+            );
+        }
+    }
+}
+
+struct SpriteLayer {
+    sprite: AnimatedSprite,
+    positions: Vec<(i32, i32)>,
+    path: Option<PathConfig>,
+}
+
+impl SpriteLayer {
+    fn draw(&mut self, panel: &mut Panel, rng: &mut ThreadRng) {
+        let Some(path) = &self.path else {
+            for (x, y) in &self.positions {
+                self.sprite.draw_at(panel, *x, *y);
+            }
+            return;
+        };
+
+        if self.sprite.has_finished() && rng.gen_bool(path.restart_chance()) {
+            self.sprite.set_animation(build_path(path, rng));
+        }
+        self.sprite.animate(panel);
+    }
+}
+
+enum Layer {
+    Tiles(TileLayer),
+    // Boxed, an AnimatedSprite is several times the size of a tile row
+    Sprite(Box<SpriteLayer>),
+}
+
+// The configuration is kept to rebuild the layer when its assets change
+struct SceneLayer {
+    config: LayerConfig,
+    layer: Layer,
+}
+
+// Runtime objects of a scene file, draws one frame per call
+pub struct Scene {
+    layers: Vec<SceneLayer>,
+    file: Option<PathBuf>,
+    base_dir: PathBuf,
+    start: Instant,
+    rng: ThreadRng,
+}
+
+impl Scene {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SceneError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(SceneError::Io)?;
+        let mut scene = Scene::parse(&content, path.parent().unwrap_or(Path::new(".")))?;
+        scene.file = Some(path.to_path_buf());
+        Ok(scene)
+    }
+
+    pub fn parse(content: &str, base_dir: &Path) -> Result<Self, SceneError> {
+        let file: SceneFile = toml::from_str(content).map_err(SceneError::Parse)?;
+        let mut rng = ThreadRng::default();
+        let layers = file
+            .layers
+            .into_iter()
+            .enumerate()
+            .map(|(number, config)| {
+                let layer = build_layer(number, &config, base_dir, &mut rng)?;
+                Ok(SceneLayer { config, layer })
+            })
+            .collect::<Result<_, SceneError>>()?;
+        Ok(Scene {
+            layers,
+            file: None,
+            base_dir: base_dir.to_path_buf(),
+            start: Instant::now(),
+            rng,
+        })
+    }
+
+    // The scene file and every file or directory an asset was loaded from
+    pub fn sources(&self) -> Vec<PathBuf> {
+        let assets = self
+            .layers
+            .iter()
+            .flat_map(|layer| layer.config.sources(&self.base_dir));
+        self.file.clone().into_iter().chain(assets).collect()
+    }
+
+    // Rebuilds the layers using one of the changed sources, or the whole scene
+    // if the scene file itself changed. Whatever fails to load keeps its previous
+    // version, the first error is returned after all other layers are rebuilt.
+    pub fn reload(&mut self, changed: &[PathBuf]) -> Result<usize, SceneError> {
+        if let Some(file) = self.file.as_ref().filter(|file| changed.contains(file)) {
+            let scene = Scene::load(file)?;
+            let rebuilt = scene.layers.len();
+            // Keeps scrolling layers where they are
+            self.layers = scene.layers;
+            return Ok(rebuilt);
+        }
+
+        let mut rebuilt = 0;
+        let mut error = None;
+        for (number, layer) in self.layers.iter_mut().enumerate() {
+            let sources = layer.config.sources(&self.base_dir);
+            if !sources.iter().any(|source| changed.contains(source)) {
+                continue;
+            }
+            match build_layer(number, &layer.config, &self.base_dir, &mut self.rng) {
+                Ok(new) => {
+                    layer.layer = new;
+                    rebuilt += 1;
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(rebuilt),
+        }
+    }
+
+    pub fn draw(&mut self, panel: &mut Panel) {
+        let elapsed = self.start.elapsed();
+        panel.clear();
+        for layer in &mut self.layers {
+            match &mut layer.layer {
+                Layer::Tiles(tiles) => tiles.draw(panel, elapsed, &mut self.rng),
+                Layer::Sprite(sprite) => sprite.draw(panel, &mut self.rng),
+            }
+        }
+    }
+}
+
+fn build_layer(
+    number: usize,
+    layer: &LayerConfig,
+    base_dir: &Path,
+    rng: &mut ThreadRng,
+) -> Result<Layer, SceneError> {
+    let invalid = |reason: &str| SceneError::Invalid(format!("layer {} {}", number, reason));
+    match layer {
+        LayerConfig::Tiles {
+            image,
+            frames,
+            x,
+            y,
+            count,
+            spacing,
+            scale,
+            scroll_interval_ms,
+            scroll_wrap,
+            random,
+        } => {
+            let images = match (image, frames) {
+                (Some(image), None) => {
+                    let path = base_dir.join(image);
+                    vec![image::open(&path).map_err(|e| AssetError::Image(path, e))?]
+                }
+                (None, Some(frames)) => load_image_directory(base_dir.join(frames))?,
+                _ => return Err(invalid("needs either image or frames")),
+            };
+            if *count == 0 {
+                return Err(invalid("needs a tile count above zero"));
+            }
+            // The wrap only matters for scrolling rows
+            let scroll_wrap = scroll_wrap.unwrap_or(*spacing);
+            if scroll_interval_ms.is_some_and(|interval| interval == 0 || scroll_wrap <= 0) {
+                return Err(invalid("scrolls with a zero interval or wrap"));
+            }
+            let tiles = (0..*count)
+                .map(|i| match random {
+                    true => rng.gen_range(0..images.len()),
+                    false => i % images.len(),
+                })
+                .collect();
+            Ok(Layer::Tiles(TileLayer {
+                images,
+                x: *x,
+                y: *y,
+                spacing: *spacing,
+                scale: *scale,
+                scroll_interval_ms: *scroll_interval_ms,
+                scroll_wrap,
+                random: *random,
+                tiles,
+                last_offset: 0,
+            }))
+        }
+        LayerConfig::Sprite {
+            frames,
+            framerate,
+            animation,
+            then,
+            loop_mode,
+            scale,
+            flip,
+            positions,
+            path,
+        } => {
+            let source = base_dir.join(frames);
+            let (frames, animations, plays) = if is_sheet(&source) {
+                let sheet = load_sheet(&source)?;
+                (sheet.frames, sheet.animations, None)
+            } else if is_animated_image(&source) {
+                let image = load_animated_image(&source)?;
+                (image.frames, vec![], image.plays)
+            } else {
+                (load_frame_directory(&source)?, vec![], None)
+            };
+            let framerate = match framerate {
+                Some(framerate) if *framerate > 0.0 => *framerate,
+                Some(_) => return Err(invalid("needs a positive framerate")),
+                // Never used, every frame has its own duration
+                None if frames.len() == 1 || frames.iter().all(|frame| frame.duration.is_some()) => 1.0,
+                None => return Err(invalid("needs a framerate")),
+            };
+            if matches!(loop_mode, LoopMode::LoopN(0)) {
+                return Err(invalid("needs a loop count above zero"));
+            }
+            // Seeded from the scene, so one rng drives all randomness of a scene
+            let mut sprite = AnimatedSprite::from_frames(frames, framerate, *loop_mode, *scale).with_seed(rng.gen());
+            sprite.set_animations(animations);
+            // The loop count of a GIF, APNG or WebP file
+            sprite.set_plays(plays);
+            let missing = |sprite: &AnimatedSprite, animation: &str| {
+                let available: Vec<&str> = sprite.animations().collect();
+                invalid(&format!(
+                    "has no animation or frame '{}', the sheet has [{}]",
+                    animation,
+                    available.join(", ")
+                ))
+            };
+            if let Some(animation) = animation {
+                if !sprite.play(animation) {
+                    return Err(missing(&sprite, animation));
+                }
+            }
+            if let Some(then) = then {
+                if !sprite.animations().any(|animation| animation == then) && sprite.frame_index(then).is_none() {
+                    return Err(missing(&sprite, then));
+                }
+                let then = then.clone();
+                sprite.set_on_finished(move |sprite| {
+                    sprite.play(&then);
+                });
+            }
+            if *flip {
+                sprite.flip();
+            }
+            if let Some(path) = path {
+                if !(0.0..=1.0).contains(&path.restart_chance()) {
+                    return Err(invalid("needs a restart_chance between 0 and 1"));
+                }
+                if let PathConfig::Points { points, curve, .. } = path {
+                    if points.len() < 2 {
+                        return Err(invalid("needs at least two path points"));
+                    }
+                    if *curve == PathCurve::Bezier && (points.len() - 1) % 3 != 0 {
+                        return Err(invalid("needs two control points between the points of a bezier path"));
+                    }
+                }
+                sprite.set_animation(build_path(path, rng));
+            }
+            Ok(Layer::Sprite(Box::new(SpriteLayer {
+                sprite,
+                positions: positions.clone(),
+                path: path.clone(),
+            })))
+        }
+    }
+}
+
+fn build_path(path: &PathConfig, rng: &mut ThreadRng) -> AnimatedPath {
+    match path {
+        PathConfig::Points {
+            points,
+            curve,
+            playback,
+            ..
+        } => AnimatedPath::new(
+            points
+                .iter()
+                .map(|point| PathPoint::new(point.x, point.y, point.duration_ms, point.flip).with_easing(point.easing))
+                .collect(),
+        )
+        .with_curve(*curve)
+        .with_playback(*playback),
+        PathConfig::Random {
+            duration_ms, easing, ..
+        } => AnimatedPath::new_random(rng, *duration_ms).with_easing(*easing),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+
+    use pnet::util::MacAddr;
+
+    use crate::linsn::{ColorFormat, FrameGeometry};
+    use crate::socket::{ChannelSender, SenderSettings};
+    use crate::test_util::TempDir;
+    use crate::virtual_receiver::VirtualReceiver;
+
+    fn manifest_dir() -> &'static Path {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[test]
+    fn test_demo_scene() {
+        let mut scene = Scene::load(manifest_dir().join("scenes/demo.toml")).unwrap();
+        assert_eq!(scene.layers.len(), 9);
+        assert_eq!(scene.sources().len(), 10);
+
+        let geometry = FrameGeometry::new(192, 192, 0);
+        let (tx, rx) = mpsc::channel();
+        let sender = Arc::new(ChannelSender::new(tx, SenderSettings::default().with_geometry(geometry)));
+        let mut panel = Panel::with_geometry(192, 192, geometry, false, false);
+        scene.draw(&mut panel);
+        panel.send(sender, MacAddr::zero()).unwrap();
+        let frame = VirtualReceiver::with_geometry(ColorFormat::default(), geometry).decode_channel(&rx).unwrap();
+        assert!(frame.image.pixels().any(|pixel| *pixel != image::Rgb([0, 0, 0])));
+    }
+
+    #[test]
+    fn test_easing_scene() {
+        let scene = Scene::load(manifest_dir().join("scenes/easing.toml")).unwrap();
+        assert_eq!(scene.layers.len(), 4);
+    }
+
+    #[test]
+    fn test_reload_keeps_working_assets() {
+        let dir = TempDir::new("scene");
+        let tile = dir.join("tile.png");
+        let scene_file = dir.join("scene.toml");
+        image::RgbImage::from_pixel(2, 2, image::Rgb([0xFF, 0, 0])).save(&tile).unwrap();
+        fs::write(&scene_file, "[[layer]]\ntype = \"tiles\"\nimage = \"tile.png\"\ny = 0\ncount = 1\nspacing = 2").unwrap();
+
+        let geometry = FrameGeometry::new(4, 4, 0);
+        let mut panel = Panel::with_geometry(4, 4, geometry, false, false);
+        let mut scene = Scene::load(&scene_file).unwrap();
+        assert_eq!(scene.sources(), vec![scene_file.clone(), tile.clone()]);
+
+        image::RgbImage::from_pixel(2, 2, image::Rgb([0, 0xFF, 0])).save(&tile).unwrap();
+        assert_eq!(scene.reload(std::slice::from_ref(&tile)).unwrap(), 1);
+
+        // A half written file is reported and the last good version stays
+        fs::write(&tile, "not a png").unwrap();
+        assert!(matches!(scene.reload(std::slice::from_ref(&tile)), Err(SceneError::Asset(AssetError::Image(..)))));
+        fs::write(&scene_file, "[[layer]]\ntype = \"tiles\"").unwrap();
+        assert!(matches!(scene.reload(std::slice::from_ref(&scene_file)), Err(SceneError::Parse(_))));
+
+        let (tx, rx) = mpsc::channel();
+        let sender = Arc::new(ChannelSender::new(tx, SenderSettings::default().with_geometry(geometry)));
+        scene.draw(&mut panel);
+        panel.send(sender, MacAddr::zero()).unwrap();
+        let mut receiver = VirtualReceiver::with_geometry(ColorFormat::default(), geometry);
+        let frame = receiver.decode_channel(&rx).unwrap();
+        assert_eq!(frame.image[(0, 0)], image::Rgb([0, 0xFF, 0]));
+    }
+
+    #[test]
+    fn test_rejects_invalid_scenes() {
+        let base_dir = manifest_dir();
+        let parse = |content: &str| Scene::parse(content, base_dir).err().unwrap();
+
+        assert!(matches!(parse("[[layer]]\ntype = \"circle\""), SceneError::Parse(_)));
+        assert!(matches!(
+            parse("[[layer]]\ntype = \"sprite\"\nframes = \"assets/dragon\"\nframerate = 1.0\nloop_mode = \"sideways\""),
+            SceneError::Parse(_)
+        ));
+        assert!(matches!(
+            parse("[[layer]]\ntype = \"tiles\"\ny = 0\ncount = 1\nspacing = 16"),
+            SceneError::Invalid(_)
+        ));
+        assert!(matches!(
+            parse("[[layer]]\ntype = \"tiles\"\nimage = \"assets/missing.png\"\ny = 0\ncount = 1\nspacing = 16"),
+            SceneError::Asset(AssetError::Image(..))
+        ));
+        assert!(matches!(
+            parse("[[layer]]\ntype = \"tiles\"\nimage = \"assets/grass.png\"\ny = 0\ncount = 0\nspacing = 16\nrandom = true"),
+            SceneError::Invalid(_)
+        ));
+        assert!(matches!(
+            parse("[[layer]]\ntype = \"tiles\"\nimage = \"assets/grass.png\"\ny = 0\ncount = 2\nspacing = 16\nscroll_interval_ms = 10\nscroll_wrap = 0"),
+            SceneError::Invalid(_)
+        ));
+        // Rows that do not scroll need no wrap
+        assert!(Scene::parse("[[layer]]\ntype = \"tiles\"\nimage = \"assets/grass.png\"\ny = 0\ncount = 2\nspacing = 0", base_dir).is_ok());
+        assert!(matches!(
+            parse("[[layer]]\ntype = \"sprite\"\nframes = \"assets/dragon\"\nframerate = 1.0\n[layer.path]\nkind = \"points\"\ncurve = \"bezier\"\npoints = [{ x = 0, y = 0 }, { x = 1, y = 1 }]"),
+            SceneError::Invalid(_)
+        ));
+    }
+}
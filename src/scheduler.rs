@@ -0,0 +1,254 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use image::Rgb;
+use pnet::util::MacAddr;
+
+use crate::encoder::Damage;
+use crate::socket::LinsnSocket;
+
+pub const DEFAULT_REFRESH_RATE: f64 = 60.0;
+
+// The latest committed frame. Producers replace the pixels as a whole, so the
+// scheduler never sends a half drawn frame. The damage collects everything
+// presented since the scheduler last picked up a frame.
+#[derive(Debug, Clone)]
+pub struct CommittedFrame {
+    pub pixels: Arc<Vec<Rgb<u8>>>,
+    pub damage: Damage,
+}
+
+impl CommittedFrame {
+    pub fn new(pixels: Vec<Rgb<u8>>) -> Self {
+        CommittedFrame {
+            pixels: Arc::new(pixels),
+            damage: Damage::full(),
+        }
+    }
+}
+
+pub type SharedFrame = Arc<Mutex<CommittedFrame>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputConfig {
+    pub refresh_rate: f64,
+    // Frames without damage are not sent again until the keepalive interval
+    // passed since the last send. Only for receivers that hold the last frame.
+    pub skip_unchanged: Option<Duration>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            refresh_rate: DEFAULT_REFRESH_RATE,
+            skip_unchanged: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OutputStats {
+    pub frames_sent: u64,
+    // Sends that finished after the next frame was already due
+    pub frames_late: u64,
+    // Refresh slots skipped entirely to catch up after late sends
+    pub frames_dropped: u64,
+    // Frames the sender failed to put on the wire, e.g. while the link was down
+    pub send_errors: u64,
+    // Unchanged frames that were not sent at all
+    pub frames_skipped: u64,
+    pub last_send_time: Duration,
+    pub max_send_time: Duration,
+}
+
+// Resends the latest committed frame at a fixed refresh rate, independent of how
+// fast new content is produced. Deadlines are derived from the start time, so
+// sleep inaccuracies do not add up over time.
+pub struct OutputScheduler {
+    stats: Arc<Mutex<OutputStats>>,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl OutputScheduler {
+    pub fn start(
+        frame: SharedFrame,
+        sender: Arc<dyn LinsnSocket + Send + Sync>,
+        dst_mac: MacAddr,
+        config: OutputConfig,
+    ) -> Self {
+        assert!(config.refresh_rate > 0.0, "Refresh rate must be positive");
+        let period = Duration::from_secs_f64(1.0 / config.refresh_rate);
+        let stats = Arc::new(Mutex::new(OutputStats::default()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let handle = thread::spawn({
+            let stats = Arc::clone(&stats);
+            let running = Arc::clone(&running);
+            move || {
+                let mut deadline = Instant::now();
+                let mut failing = false;
+                let mut last_sent: Option<Instant> = None;
+                while running.load(Ordering::Relaxed) {
+                    let (current, damage) = {
+                        let mut frame = frame.lock().expect("Mutex Poisend");
+                        (Arc::clone(&frame.pixels), frame.damage.take())
+                    };
+
+                    let unchanged = damage.is_empty() && last_sent.is_some_and(|sent| {
+                        config
+                            .skip_unchanged
+                            .is_some_and(|keepalive| sent.elapsed() < keepalive)
+                    });
+                    if unchanged {
+                        stats.lock().expect("Mutex Poisend").frames_skipped += 1;
+                        deadline += period;
+                        let now = Instant::now();
+                        if now < deadline {
+                            thread::sleep(deadline - now);
+                        }
+                        continue;
+                    }
+
+                    let before = Instant::now();
+                    let result = sender.send_damaged(&current, dst_mac, &damage);
+                    let send_time = before.elapsed();
+                    match result {
+                        Ok(()) => last_sent = Some(before),
+                        // The chunks may not have been encoded, keep them for the
+                        // next attempt
+                        Err(_) => frame.lock().expect("Mutex Poisend").damage.merge(&damage),
+                    }
+
+                    // Errors are only logged when they start and stop, the
+                    // scheduler keeps going and the sender reconnects on its own
+                    match (&result, failing) {
+                        (Err(e), false) => eprintln!("Failed to send frame: {}", e),
+                        (Ok(()), true) => eprintln!("Sending frames again"),
+                        _ => (),
+                    }
+                    failing = result.is_err();
+
+                    deadline += period;
+                    let now = Instant::now();
+                    let mut stats = stats.lock().expect("Mutex Poisend");
+                    match failing {
+                        true => stats.send_errors += 1,
+                        false => stats.frames_sent += 1,
+                    }
+                    stats.last_send_time = send_time;
+                    stats.max_send_time = stats.max_send_time.max(send_time);
+
+                    if now <= deadline {
+                        drop(stats);
+                        thread::sleep(deadline - now);
+                        continue;
+                    }
+
+                    // Send the next frame right away, but skip the slots that
+                    // have already passed completely
+                    stats.frames_late += 1;
+                    let missed = ((now - deadline).as_nanos() / period.as_nanos()) as u32;
+                    stats.frames_dropped += missed as u64;
+                    deadline += period * missed;
+                }
+            }
+        });
+
+        OutputScheduler {
+            stats,
+            running,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn stats(&self) -> OutputStats {
+        *self.stats.lock().expect("Mutex Poisend")
+    }
+}
+
+impl Drop for OutputScheduler {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+
+    use crate::socket::SenderError;
+
+    struct SlowSocket {
+        sends: AtomicU64,
+        delay: Duration,
+    }
+
+    impl LinsnSocket for SlowSocket {
+        fn send_damaged(
+            &self,
+            _image: &[Rgb<u8>],
+            _dst_mac: MacAddr,
+            _damage: &Damage,
+        ) -> Result<(), SenderError> {
+            self.sends.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(self.delay);
+            Ok(())
+        }
+    }
+
+    fn run(delay: Duration, config: OutputConfig, duration: Duration) -> OutputStats {
+        let frame: SharedFrame = Arc::new(Mutex::new(CommittedFrame::new(vec![])));
+        let socket = Arc::new(SlowSocket {
+            sends: AtomicU64::new(0),
+            delay,
+        });
+        let scheduler = OutputScheduler::start(frame, socket.clone(), MacAddr::zero(), config);
+        thread::sleep(duration);
+        let stats = scheduler.stats();
+        drop(scheduler);
+        assert!(socket.sends.load(Ordering::Relaxed) >= stats.frames_sent);
+        stats
+    }
+
+    #[test]
+    fn test_paces_to_refresh_rate() {
+        let config = OutputConfig {
+            refresh_rate: 100.0,
+            ..Default::default()
+        };
+        let stats = run(Duration::ZERO, config, Duration::from_millis(500));
+        // Roughly 50 frames, without sending as fast as possible
+        assert!((25..=55).contains(&stats.frames_sent), "{:?}", stats);
+    }
+
+    #[test]
+    fn test_reports_late_frames() {
+        let config = OutputConfig {
+            refresh_rate: 100.0,
+            ..Default::default()
+        };
+        let stats = run(Duration::from_millis(25), config, Duration::from_millis(300));
+        assert!(stats.frames_late > 0, "{:?}", stats);
+        assert!(stats.frames_dropped > 0, "{:?}", stats);
+        assert!(stats.max_send_time >= Duration::from_millis(25));
+    }
+
+    #[test]
+    fn test_skips_unchanged_frames() {
+        let config = OutputConfig {
+            refresh_rate: 100.0,
+            skip_unchanged: Some(Duration::from_millis(200)),
+        };
+        let stats = run(Duration::ZERO, config, Duration::from_millis(500));
+        // The first frame plus a keepalive every 200ms
+        assert!((2..=4).contains(&stats.frames_sent), "{:?}", stats);
+        assert!(stats.frames_skipped > 25, "{:?}", stats);
+    }
+}
@@ -1,4 +1,5 @@
 use std::f64::consts::PI;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use gstreamer::glib;
@@ -8,6 +9,7 @@ use gstreamer::prelude::*;
 use gstreamer::BufferRef;
 use gstreamer::Caps;
 use gstreamer::Fraction;
+use gstreamer::MessageView;
 use gstreamer::{ElementFactory, Pipeline};
 use gstreamer_app::AppSink;
 use gstreamer_video::VideoInfo;
@@ -38,20 +40,29 @@ fn yuv_to_rgb(y: f32, u: f32, v: f32) -> [u8; 3] {
 //     }
 // }
 
-pub fn init_gstreamer<F>(play_file: bool, panelx: usize, panely: usize, on_frame: F)
+pub enum CaptureSource {
+    Screen,
+    File(PathBuf),
+}
+
+// The pipeline keeps running in the background until it is set to Null, e.g.
+// by wait_for_end
+pub fn init_gstreamer<F>(source: CaptureSource, panelx: usize, panely: usize, on_frame: F) -> Pipeline
 where
     F: Fn(&BufferRef, u32, u32, u32) + Send + Sync + 'static,
 {
+    let play_file = matches!(source, CaptureSource::File(_));
+
     gstreamer::init().expect("Failed to initialize GStreamer");
 
     // Create a GStreamer pipeline
     let pipeline = Pipeline::with_name("screen-capture");
 
     // Set up the source element
-    let src = if play_file {
+    let src = if let CaptureSource::File(path) = &source {
         // filesrc with decodebin for video files
         let filesrc = ElementFactory::make("filesrc")
-            .property("location", "big_buck_bunny_1080p_h264.mov") // Set the path to your file
+            .property("location", path.to_string_lossy().to_string())
             .build()
             .unwrap();
         let decode = ElementFactory::make("decodebin").build().unwrap();
@@ -209,4 +220,21 @@ where
     // Start the pipeline
     dbg!(pipeline.set_state(gstreamer::State::Playing))
         .expect("Unable to set pipeline to `Playing` state");
+    pipeline
+}
+
+// Blocks until the video ended or the pipeline failed, then stops it
+pub fn wait_for_end(pipeline: &Pipeline) {
+    let bus = pipeline.bus().expect("Pipeline without bus");
+    for message in bus.iter_timed(gstreamer::ClockTime::NONE) {
+        match message.view() {
+            MessageView::Eos(..) => break,
+            MessageView::Error(err) => {
+                eprintln!("GStreamer error: {} ({:?})", err.error(), err.debug());
+                break;
+            }
+            _ => (),
+        }
+    }
+    let _ = pipeline.set_state(gstreamer::State::Null);
 }
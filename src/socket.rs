@@ -1,250 +1,805 @@
-use crate::linsn::HEADER_SIZE;
-use crate::linsn::{
-    pixel_to_bytes, ColorFormat, LinsnHeader, LinsnSenderPacket, PAYLOAD_SIZE_SENDER,
-};
+use crate::color::{Brightness, Calibration};
+use crate::encoder::{Damage, FrameEncoder, PACKET_SIZE};
+use crate::linsn::{ColorFormat, FrameGeometry};
+use crate::pcap::PcapWriter;
 use image::Rgb;
 use pnet::datalink;
 use pnet::datalink::Channel;
+use pnet::datalink::DataLinkReceiver;
 use pnet::datalink::DataLinkSender;
-use pnet::packet::ethernet::MutableEthernetPacket;
-use pnet::packet::Packet;
+use pnet::datalink::NetworkInterface;
 use pnet::util::MacAddr;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::sync::{atomic, Arc, Mutex, MutexGuard};
 
 use libc::{
     c_void, close, if_nametoindex, iovec, mmsghdr, sendmmsg, sockaddr_ll, socket, AF_PACKET,
     ETH_ALEN, ETH_P_ALL, SOCK_RAW,
 };
 use std::ffi::CString;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter};
 use std::mem;
+use std::path::Path;
 use std::ptr;
+use std::time::{Duration, Instant, SystemTime};
+
+// Reopening a vanished interface is attempted at most this often
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+// Pause after a failed read, so a broken interface does not spin a listener
+pub const RECEIVE_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub enum SenderError {
+    InterfaceNotFound(String),
+    UnsupportedChannel(String),
+    // The interface went away and could not be reopened yet
+    LinkDown(String),
+    Io(io::Error),
+    WrongFrameSize { expected: usize, actual: usize },
+    // The receiving end of a ChannelSender is gone
+    ChannelClosed,
+}
+
+impl fmt::Display for SenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SenderError::InterfaceNotFound(name) => {
+                write!(f, "network interface {} not found", name)
+            }
+            SenderError::UnsupportedChannel(name) => {
+                write!(f, "unhandled channel type on {}", name)
+            }
+            SenderError::LinkDown(name) => write!(f, "link on {} is down", name),
+            SenderError::Io(e) => write!(f, "{}", e),
+            SenderError::WrongFrameSize { expected, actual } => write!(
+                f,
+                "frame has {} pixels, expected {}",
+                actual, expected
+            ),
+            SenderError::ChannelClosed => write!(f, "channel closed"),
+        }
+    }
+}
+
+impl std::error::Error for SenderError {}
+
+impl From<io::Error> for SenderError {
+    fn from(e: io::Error) -> Self {
+        SenderError::Io(e)
+    }
+}
 
-const BYTES_PER_PIXEL: usize = 3;
-const CHUNK_SIZE: usize = PAYLOAD_SIZE_SENDER / BYTES_PER_PIXEL;
 pub trait LinsnSocket {
-    fn send(&self, image: &Vec<Rgb<u8>>, dst_mac: MacAddr);
+    // Only the chunks in damage changed since the previous frame sent through
+    // this socket, all others may come from the encoder's cache
+    fn send_damaged(
+        &self,
+        image: &[Rgb<u8>],
+        dst_mac: MacAddr,
+        damage: &Damage,
+    ) -> Result<(), SenderError>;
+
+    fn send(&self, image: &[Rgb<u8>], dst_mac: MacAddr) -> Result<(), SenderError> {
+        self.send_damaged(image, dst_mac, &Damage::full())
+    }
 }
 
+// Every frame handed to a sender has to cover the whole canvas, otherwise the
+// receiver cards would be fed a different number of chunks
+fn check_geometry(image: &[Rgb<u8>], geometry: &FrameGeometry) -> Result<(), SenderError> {
+    if image.len() != geometry.pixel_count() {
+        return Err(SenderError::WrongFrameSize {
+            expected: geometry.pixel_count(),
+            actual: image.len(),
+        });
+    }
+    Ok(())
+}
+
+fn find_interface(interface_name: &str) -> Result<NetworkInterface, SenderError> {
+    datalink::interfaces()
+        .into_iter()
+        .find(|iface| iface.name == interface_name)
+        .ok_or_else(|| SenderError::InterfaceNotFound(interface_name.to_string()))
+}
+
+// Sends one Ethernet frame outside of the frame senders, e.g. a CONFIG command
+pub fn send_frame(interface_name: &str, frame: &[u8]) -> Result<(), SenderError> {
+    let interface = find_interface(interface_name)?;
+    let mut tx = match datalink::channel(&interface, Default::default())? {
+        Channel::Ethernet(tx, _) => tx,
+        _ => return Err(SenderError::UnsupportedChannel(interface_name.to_string())),
+    };
+    match tx.send_to(frame, None) {
+        Some(result) => Ok(result?),
+        None => Err(SenderError::LinkDown(interface_name.to_string())),
+    }
+}
+
+// Receiving end of an interface, for the tools listening on the wire
+pub fn open_receiver(
+    interface_name: &str,
+    config: datalink::Config,
+) -> Result<Box<dyn DataLinkReceiver>, SenderError> {
+    let interface = find_interface(interface_name)?;
+    match datalink::channel(&interface, config)? {
+        Channel::Ethernet(_, rx) => Ok(rx),
+        _ => Err(SenderError::UnsupportedChannel(interface_name.to_string())),
+    }
+}
+
+// Errors after which the socket is useless, e.g. because the NIC was unplugged
+pub fn is_link_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ENETDOWN | libc::ENXIO | libc::ENODEV | libc::EBADF)
+    )
+}
+
+// Keeps a connection to an interface and reopens it once the link went away,
+// so a replugged NIC picks up sending again
+struct Reconnecting<T> {
+    interface_name: String,
+    open: fn(&str) -> Result<T, SenderError>,
+    connection: Option<T>,
+    last_attempt: Option<Instant>,
+}
+
+impl<T> Reconnecting<T> {
+    fn new(interface_name: &str, open: fn(&str) -> Result<T, SenderError>) -> Result<Self, SenderError> {
+        Ok(Reconnecting {
+            interface_name: interface_name.to_string(),
+            open,
+            connection: Some(open(interface_name)?),
+            last_attempt: None,
+        })
+    }
+
+    fn get(&mut self) -> Result<&mut T, SenderError> {
+        if self.connection.is_none() {
+            if let Some(last_attempt) = self.last_attempt {
+                if last_attempt.elapsed() < RECONNECT_INTERVAL {
+                    return Err(SenderError::LinkDown(self.interface_name.clone()));
+                }
+            }
+            self.last_attempt = Some(Instant::now());
+            self.connection = Some((self.open)(&self.interface_name)?);
+            eprintln!("Reopened {}", self.interface_name);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    // Drops the connection if the error will not go away by sending again
+    fn fail(&mut self, e: io::Error) -> SenderError {
+        if is_link_error(&e) {
+            self.connection = None;
+            self.last_attempt = None;
+        }
+        SenderError::Io(e)
+    }
+}
+
+// Options every sender backend applies while encoding a frame
+#[derive(Clone, Default)]
+pub struct SenderSettings {
+    pub color_format: ColorFormat,
+    pub brightness: Brightness,
+    pub calibration: Calibration,
+    pub geometry: FrameGeometry,
+}
+
+impl SenderSettings {
+    pub fn with_color_format(mut self, color_format: ColorFormat) -> Self {
+        self.color_format = color_format;
+        self
+    }
+
+    pub fn with_brightness(mut self, brightness: Brightness) -> Self {
+        self.brightness = brightness;
+        self
+    }
+
+    pub fn with_calibration(mut self, calibration: Calibration) -> Self {
+        self.calibration = calibration;
+        self
+    }
+
+    pub fn with_geometry(mut self, geometry: FrameGeometry) -> Self {
+        self.geometry = geometry;
+        self
+    }
+}
+
+// Settings and encoder embedded in every sender backend. Clones of a sender share
+// the encoder, it is reconfigured before every frame.
 #[derive(Clone)]
-pub struct SimpleSocketSender {
-    tx: Arc<Mutex<Box<dyn DataLinkSender>>>,
+struct SenderCore {
+    settings: SenderSettings,
     src_mac: MacAddr,
+    encoder: Arc<Mutex<FrameEncoder>>,
+}
+
+impl SenderCore {
+    fn new(src_mac: MacAddr, settings: SenderSettings) -> Self {
+        let encoder = FrameEncoder::new(
+            settings.geometry,
+            src_mac,
+            MacAddr::broadcast(),
+            settings.color_format,
+        );
+        SenderCore {
+            settings,
+            src_mac,
+            encoder: Arc::new(Mutex::new(encoder)),
+        }
+    }
+
+    // Encodes a frame, the packets stay in the returned encoder until it is unlocked
+    fn encode(
+        &self,
+        image: &[Rgb<u8>],
+        dst_mac: MacAddr,
+        damage: &Damage,
+    ) -> Result<MutexGuard<'_, FrameEncoder>, SenderError> {
+        let settings = &self.settings;
+        check_geometry(image, &settings.geometry)?;
+        let mut encoder = self.encoder.lock().expect("Failed to acquire lock on encoder");
+        encoder.configure(settings.geometry, self.src_mac, dst_mac, settings.color_format);
+        encoder.encode_damaged(image, &settings.calibration.channel_luts(&settings.brightness), damage);
+        Ok(encoder)
+    }
+}
+
+// Sender backends on an interface use its MAC as the source of every frame
+pub fn interface_mac(interface_name: &str) -> Result<MacAddr, SenderError> {
+    Ok(find_interface(interface_name)?.mac.unwrap_or(MacAddr::broadcast()))
+}
+
+#[derive(Clone)]
+pub struct SimpleSocketSender {
+    tx: Arc<Mutex<Reconnecting<Box<dyn DataLinkSender>>>>,
+    core: SenderCore,
 }
 
 impl SimpleSocketSender {
-    pub fn new(interface_name: &str) -> Self {
-        let interfaces = datalink::interfaces();
-        let interface = interfaces
-            .into_iter()
-            .find(|iface| iface.name == interface_name)
-            .expect("Network interface not found");
-
-        let channel: datalink::Channel = datalink::channel(&interface, Default::default()).unwrap();
-        let tx = match channel {
-            Channel::Ethernet(tx, _) => tx,
-            _ => panic!("Unhandled channel type"),
-        };
-        Self {
-            src_mac: interface.mac.unwrap_or(MacAddr::broadcast()),
+    pub fn new(interface_name: &str, settings: SenderSettings) -> Result<Self, SenderError> {
+        let src_mac = interface_mac(interface_name)?;
+        let tx = Reconnecting::new(interface_name, SimpleSocketSender::open)?;
+        Ok(Self {
             tx: Arc::new(Mutex::new(tx)),
+            core: SenderCore::new(src_mac, settings),
+        })
+    }
+
+    fn open(interface_name: &str) -> Result<Box<dyn DataLinkSender>, SenderError> {
+        let interface = find_interface(interface_name)?;
+        match datalink::channel(&interface, Default::default())? {
+            Channel::Ethernet(tx, _) => Ok(tx),
+            _ => Err(SenderError::UnsupportedChannel(interface_name.to_string())),
         }
     }
 }
 
 impl LinsnSocket for SimpleSocketSender {
-    fn send(&self, image: &Vec<Rgb<u8>>, dst_mac: MacAddr) {
-        let before = Instant::now();
-        let tx = Arc::clone(&self.tx);
+    fn send_damaged(
+        &self,
+        image: &[Rgb<u8>],
+        dst_mac: MacAddr,
+        damage: &Damage,
+    ) -> Result<(), SenderError> {
+        let encoder = self.core.encode(image, dst_mac, damage)?;
 
         // Lock the transmitter to send the image
-        let mut tx = tx.lock().expect("Failed to acquire lock on transmitter");
-        let chunks = image.chunks(CHUNK_SIZE);
-        for (package_id, chunk) in chunks.enumerate() {
-            // Convert the pixel data to bytes
-            let mut payload = vec![0_u8; PAYLOAD_SIZE_SENDER];
-            for (index, pixel) in chunk.iter().enumerate() {
-                let pbytes = pixel_to_bytes(ColorFormat::BRG, pixel);
-                payload[index * BYTES_PER_PIXEL..(index + 1) * BYTES_PER_PIXEL]
-                    .copy_from_slice(&pbytes);
-            }
-
-            // Create the whole payload
-            let packet = LinsnSenderPacket {
-                header: match package_id {
-                    0 => LinsnHeader::chunk_start(self.src_mac),
-                    _ => LinsnHeader::empty(package_id as u32),
-                },
-                payload: payload.try_into().unwrap(),
-            };
-            let ethernet_packet = packet.as_ethernet(Some(self.src_mac), Some(dst_mac));
-            match tx.send_to(ethernet_packet.packet(), None) {
+        let mut link = self
+            .tx
+            .lock()
+            .expect("Failed to acquire lock on transmitter");
+        for packet in encoder.packets() {
+            let result = link.get()?.send_to(packet, None);
+            match result {
                 Some(Ok(_)) => (),
-                Some(Err(e)) => eprintln!("Failed to send packet: {}", e),
-                None => eprintln!("Failed to send packet: No response"),
+                Some(Err(e)) => return Err(link.fail(e)),
+                None => return Err(SenderError::Io(io::Error::other("no response"))),
             }
         }
-        let now = Instant::now();
-        println!("Time for sending: {:.0?}", (now - before));
+        Ok(())
     }
 }
 
-#[derive(Clone)]
-pub struct BatchedSocketSender {
+// Raw AF_PACKET socket, closed when dropped
+struct RawSocket {
     if_index: u32,
-    sockfd: i32,
-    src_mac: MacAddr,
+    fd: i32,
 }
 
-impl BatchedSocketSender {
-    pub fn new(interface_name: &str) -> Self {
-        let interfaces = datalink::interfaces();
-        let interface = interfaces
-            .into_iter()
-            .find(|iface| iface.name == interface_name)
-            .expect("Network interface not found");
-
-        let src_mac: MacAddr = interface.mac.or(Some(MacAddr::broadcast())).unwrap();
-
+impl RawSocket {
+    fn open(interface_name: &str) -> Result<Self, SenderError> {
+        let if_name = CString::new(interface_name)
+            .map_err(|_| SenderError::InterfaceNotFound(interface_name.to_string()))?;
         unsafe {
-            let if_name = CString::new(interface_name).unwrap();
             let if_index = if_nametoindex(if_name.as_ptr());
             if if_index == 0 {
-                panic!("Failed to get interface index");
+                return Err(SenderError::InterfaceNotFound(interface_name.to_string()));
             }
 
-            let sockfd = socket(AF_PACKET, SOCK_RAW, (ETH_P_ALL as u16).to_be() as i32);
-            if sockfd == -1 {
-                panic!("Failed to create socket");
+            let fd = socket(AF_PACKET, SOCK_RAW, (ETH_P_ALL as u16).to_be() as i32);
+            if fd == -1 {
+                return Err(io::Error::last_os_error().into());
             }
+            let raw_socket = RawSocket { if_index, fd };
 
             // Setting buffer size
             let mut n: libc::c_int = 0;
             let mut n_len = mem::size_of::<libc::c_int>() as libc::socklen_t;
             let ret = libc::getsockopt(
-                sockfd,
+                fd,
                 libc::SOL_SOCKET,
                 libc::SO_SNDBUF,
                 &mut n as *mut i32 as *mut libc::c_void,
                 &mut n_len,
             );
             if ret != 0 {
-                panic!("failed to get socket params");
+                return Err(io::Error::last_os_error().into());
             }
             println!("Send Buffer size: {:}", n);
 
             n = 1024 * 1024 * 1024;
             let ret = libc::setsockopt(
-                sockfd,
+                fd,
                 libc::SOL_SOCKET,
                 libc::SO_SNDBUF,
                 &n as *const _ as *const libc::c_void,
                 mem::size_of_val(&n) as libc::socklen_t,
             );
             if ret != 0 {
-                panic!("failed to get socket params");
+                return Err(io::Error::last_os_error().into());
             }
 
             println!("Send Buffer size: {:}", n);
-            Self {
-                if_index,
-                sockfd,
-                src_mac,
+            Ok(raw_socket)
+        }
+    }
+
+    fn send_packets<'a>(&self, packets: impl Iterator<Item = &'a [u8]>) -> io::Result<()> {
+        let mut socket_address: sockaddr_ll = unsafe { mem::zeroed() };
+        socket_address.sll_family = AF_PACKET as u16;
+        socket_address.sll_protocol = (ETH_P_ALL as u16).to_be();
+        socket_address.sll_ifindex = self.if_index as i32;
+        socket_address.sll_halen = ETH_ALEN as u8;
+
+        // iovecs point into the packet buffers, no payload is copied
+        let mut iovecs: Vec<iovec> = packets
+            .map(|packet| iovec {
+                iov_base: packet.as_ptr() as *mut c_void,
+                iov_len: packet.len(),
+            })
+            .collect();
+        let mut msgs: Vec<mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iov| {
+                let mut msg: mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg.msg_hdr.msg_name = &mut socket_address as *mut sockaddr_ll as *mut c_void;
+                msg.msg_hdr.msg_namelen = mem::size_of::<sockaddr_ll>() as u32;
+                msg
+            })
+            .collect();
+        self.send_all(&mut msgs)
+    }
+
+    // sendmmsg may give up early when the socket buffer is full
+    fn send_all(&self, msgs: &mut [mmsghdr]) -> io::Result<()> {
+        let mut sent = 0;
+        while sent < msgs.len() {
+            let ret = unsafe {
+                sendmmsg(
+                    self.fd,
+                    msgs[sent..].as_mut_ptr(),
+                    (msgs.len() - sent) as u32,
+                    0,
+                )
+            };
+            if ret == -1 {
+                return Err(io::Error::last_os_error());
             }
+            sent += ret as usize;
         }
+        Ok(())
+    }
+}
+
+impl Drop for RawSocket {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.fd);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BatchedSocketSender {
+    socket: Arc<Mutex<Reconnecting<RawSocket>>>,
+    core: SenderCore,
+}
+
+impl BatchedSocketSender {
+    pub fn new(interface_name: &str, settings: SenderSettings) -> Result<Self, SenderError> {
+        let src_mac = interface_mac(interface_name)?;
+        let socket = Reconnecting::new(interface_name, RawSocket::open)?;
+        Ok(Self {
+            socket: Arc::new(Mutex::new(socket)),
+            core: SenderCore::new(src_mac, settings),
+        })
+    }
+
+    // Sends already assembled Ethernet frames unchanged, e.g. from a capture
+    pub fn send_frames(&self, frames: &[Vec<u8>]) -> Result<(), SenderError> {
+        let mut link = self.socket.lock().expect("Failed to acquire lock on socket");
+        let result = link.get()?.send_packets(frames.iter().map(Vec::as_slice));
+        result.map_err(|e| link.fail(e))
     }
 }
 
 impl LinsnSocket for BatchedSocketSender {
-    fn send(&self, image: &Vec<Rgb<u8>>, dst_mac: MacAddr) {
-        let before: Instant = Instant::now();
-
-        let mut socket_address: sockaddr_ll = sockaddr_ll {
-            sll_family: AF_PACKET as u16,
-            sll_protocol: (ETH_P_ALL as u16).to_be(),
-            sll_ifindex: self.if_index as i32,
-            sll_hatype: 0,
-            sll_pkttype: 0,
-            sll_halen: ETH_ALEN as u8,
-            sll_addr: [
-                dst_mac.0, dst_mac.1, dst_mac.2, dst_mac.3, dst_mac.4, dst_mac.5, 0, 0,
-            ],
-        };
-        const MAX_CHUNK_COUNT: usize = 1100;
+    fn send_damaged(
+        &self,
+        image: &[Rgb<u8>],
+        dst_mac: MacAddr,
+        damage: &Damage,
+    ) -> Result<(), SenderError> {
+        let encoder = self.core.encode(image, dst_mac, damage)?;
+
+        // Frame timing is tracked by the OutputScheduler
+        let mut link = self.socket.lock().expect("Failed to acquire lock on socket");
+        let result = link.get()?.send_packets(encoder.packets());
+        result.map_err(|e| link.fail(e))
+    }
+}
+
+// From linux/if_packet.h, not every libc release exposes the ring API
+const PACKET_VERSION: libc::c_int = 10;
+const PACKET_TX_RING: libc::c_int = 13;
+const PACKET_QDISC_BYPASS: libc::c_int = 20;
+const TPACKET_V2: libc::c_int = 1;
+const TP_STATUS_AVAILABLE: u32 = 0;
+const TP_STATUS_SEND_REQUEST: u32 = 1;
+const TP_STATUS_WRONG_FORMAT: u32 = 4;
+
+#[repr(C)]
+struct TpacketReq {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+}
+
+#[repr(C)]
+struct Tpacket2Hdr {
+    tp_status: u32,
+    tp_len: u32,
+    tp_snaplen: u32,
+    tp_mac: u16,
+    tp_net: u16,
+    tp_sec: u32,
+    tp_nsec: u32,
+    tp_vlan_tci: u16,
+    tp_vlan_tpid: u16,
+    tp_padding: [u8; 4],
+}
+
+// Without PACKET_TX_HAS_OFF the kernel expects the frame right behind the
+// 16 byte aligned slot header
+const RING_DATA_OFFSET: usize = (mem::size_of::<Tpacket2Hdr>() + 15) & !15;
+const RING_FRAME_SIZE: usize = 2048;
+const RING_BLOCK_SIZE: usize = 1 << 16;
+// Room for almost four frames of the full 1024x512 canvas
+const RING_BLOCK_COUNT: usize = 128;
+const RING_FRAME_COUNT: usize = RING_BLOCK_COUNT * RING_BLOCK_SIZE / RING_FRAME_SIZE;
+
+// TPACKET_V2 transmit ring shared with the kernel
+struct TxRing {
+    fd: i32,
+    ring: *mut u8,
+    cursor: usize,
+}
+
+// The mapping is only ever touched by the thread holding the sender's lock
+unsafe impl Send for TxRing {}
+
+impl TxRing {
+    fn open(interface_name: &str) -> Result<Self, SenderError> {
+        let if_name = CString::new(interface_name)
+            .map_err(|_| SenderError::InterfaceNotFound(interface_name.to_string()))?;
         unsafe {
-            let chunks = image.chunks(CHUNK_SIZE);
-            let chunk_count = chunks.len();
-            if chunk_count > MAX_CHUNK_COUNT {
-                panic!("Too many chunks!");
-            }
-            let mut iovecs = [mem::zeroed::<iovec>(); MAX_CHUNK_COUNT];
-            let mut msgs = [mem::zeroed::<mmsghdr>(); MAX_CHUNK_COUNT];
-
-            let mut ethernet_packets = [[0u8; MutableEthernetPacket::minimum_packet_size()
-                + PAYLOAD_SIZE_SENDER
-                + HEADER_SIZE]; MAX_CHUNK_COUNT];
-
-            for (package_id, chunk) in chunks.enumerate() {
-                // Convert the pixel data to bytes
-                let mut payload = vec![0 as u8; PAYLOAD_SIZE_SENDER];
-                for (index, pixel) in chunk.iter().enumerate() {
-                    let pbytes = pixel_to_bytes(ColorFormat::BRG, pixel);
-                    payload[index * BYTES_PER_PIXEL..(index + 1) * BYTES_PER_PIXEL]
-                        .copy_from_slice(&pbytes);
-                }
+            let if_index = if_nametoindex(if_name.as_ptr());
+            if if_index == 0 {
+                return Err(SenderError::InterfaceNotFound(interface_name.to_string()));
+            }
 
-                // Create the whole payload
-                let packet: LinsnSenderPacket = LinsnSenderPacket {
-                    header: match package_id {
-                        0 => LinsnHeader::chunk_start(self.src_mac),
-                        _ => LinsnHeader::empty(package_id as u32),
-                    },
-                    payload: payload.try_into().unwrap(),
-                };
-                ethernet_packets[package_id].copy_from_slice(
-                    &packet
-                        .as_ethernet(Some(self.src_mac), Some(dst_mac))
-                        .packet(),
-                );
+            let fd = socket(AF_PACKET, SOCK_RAW, (ETH_P_ALL as u16).to_be() as i32);
+            if fd == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+            // Dropping closes the socket on every error below
+            let mut tx_ring = TxRing {
+                fd,
+                ring: ptr::null_mut(),
+                cursor: 0,
+            };
 
-                // iovec points to the packet buffer
-                iovecs[package_id].iov_base = ethernet_packets[package_id].as_ptr() as *mut c_void;
-                iovecs[package_id].iov_len = ethernet_packets[package_id].len();
-
-                // mmsghdr contains message headers
-                msgs[package_id].msg_hdr.msg_iov = &mut iovecs[package_id];
-                msgs[package_id].msg_hdr.msg_iovlen = 1;
-                msgs[package_id].msg_hdr.msg_name =
-                    &mut socket_address as *mut sockaddr_ll as *mut c_void;
-                msgs[package_id].msg_hdr.msg_namelen = mem::size_of::<sockaddr_ll>() as u32;
-                msgs[package_id].msg_hdr.msg_control = ptr::null_mut();
-                msgs[package_id].msg_hdr.msg_controllen = 0;
-                msgs[package_id].msg_hdr.msg_flags = 0;
-                msgs[package_id].msg_len = 0;
-            }
-            // let before_sending = Instant::now();
-            let ret = sendmmsg(
-                self.sockfd,
-                msgs.as_mut_ptr(),
-                ethernet_packets.len() as u32,
+            tx_ring.set_option(PACKET_VERSION, &TPACKET_V2)?;
+            // Skipping the qdisc layer is only an optimization, older kernels lack it
+            let _ = tx_ring.set_option(PACKET_QDISC_BYPASS, &1);
+            let request = TpacketReq {
+                tp_block_size: RING_BLOCK_SIZE as u32,
+                tp_block_nr: RING_BLOCK_COUNT as u32,
+                tp_frame_size: RING_FRAME_SIZE as u32,
+                tp_frame_nr: RING_FRAME_COUNT as u32,
+            };
+            tx_ring.set_option(PACKET_TX_RING, &request)?;
+
+            let ring = libc::mmap(
+                ptr::null_mut(),
+                RING_BLOCK_COUNT * RING_BLOCK_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
                 0,
             );
+            if ring == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error().into());
+            }
+            tx_ring.ring = ring as *mut u8;
 
-            if ret == -1 {
-                eprintln!("Failed to send packets");
-                close(self.sockfd);
-                return;
-            } else {
-                let now = Instant::now();
-                // // println!("Time for preparing: {:.0?}", before-before_sending);
-                // // println!("Time for sending: {:.0?}" , now-before_sending);
-                if (now - before).as_millis() > (1000 / 60) {
-                    println!(
-                        "Time for sending dropped below 60 fps: {:.0?}",
-                        (now - before)
-                    );
-                }
+            let mut socket_address: sockaddr_ll = mem::zeroed();
+            socket_address.sll_family = AF_PACKET as u16;
+            socket_address.sll_protocol = (ETH_P_ALL as u16).to_be();
+            socket_address.sll_ifindex = if_index as i32;
+            let ret = libc::bind(
+                fd,
+                &socket_address as *const sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<sockaddr_ll>() as libc::socklen_t,
+            );
+            if ret != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            Ok(tx_ring)
+        }
+    }
+
+    fn set_option<T>(&self, name: libc::c_int, value: &T) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(
+                self.fd,
+                libc::SOL_PACKET,
+                name,
+                value as *const T as *const libc::c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn header(&self, slot: usize) -> *mut Tpacket2Hdr {
+        unsafe { self.ring.add(slot * RING_FRAME_SIZE) as *mut Tpacket2Hdr }
+    }
+
+    // Waits until the kernel is done with the next slot and returns its frame buffer
+    fn next_slot(&mut self) -> io::Result<&mut [u8]> {
+        let header = self.header(self.cursor);
+        let mut attempts = 0;
+        loop {
+            let status = unsafe { ptr::read_volatile(&(*header).tp_status) };
+            atomic::fence(atomic::Ordering::Acquire);
+            if status == TP_STATUS_AVAILABLE {
+                break;
+            }
+            if status & TP_STATUS_WRONG_FORMAT != 0 {
+                unsafe { ptr::write_volatile(&mut (*header).tp_status, TP_STATUS_AVAILABLE) };
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "kernel rejected a ring frame",
+                ));
+            }
+            // The ring is full, hand what is queued to the kernel and wait for it
+            if attempts == 100 {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
             }
+            attempts += 1;
+            self.flush()?;
         }
+
+        unsafe {
+            let data = (header as *mut u8).add(RING_DATA_OFFSET);
+            Ok(std::slice::from_raw_parts_mut(data, PACKET_SIZE))
+        }
+    }
+
+    // Queues the slot returned by next_slot for transmission
+    fn commit(&mut self) {
+        let header = self.header(self.cursor);
+        unsafe {
+            (*header).tp_len = PACKET_SIZE as u32;
+            atomic::fence(atomic::Ordering::Release);
+            ptr::write_volatile(&mut (*header).tp_status, TP_STATUS_SEND_REQUEST);
+        }
+        self.cursor = (self.cursor + 1) % RING_FRAME_COUNT;
+    }
+
+    // Fills one slot per packet and hands the whole frame to the kernel at once
+    fn send_packets<'a>(&mut self, packets: impl Iterator<Item = &'a [u8]>) -> io::Result<()> {
+        for packet in packets {
+            self.next_slot()?.copy_from_slice(packet);
+            self.commit();
+        }
+        self.flush()
+    }
+
+    // Blocks until the kernel has sent every queued slot
+    fn flush(&self) -> io::Result<()> {
+        let ret = unsafe { libc::send(self.fd, ptr::null(), 0, 0) };
+        match ret {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for TxRing {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.ring.is_null() {
+                libc::munmap(
+                    self.ring as *mut libc::c_void,
+                    RING_BLOCK_COUNT * RING_BLOCK_SIZE,
+                );
+            }
+            close(self.fd);
+        }
+    }
+}
+
+// Copies the encoded packets straight into the slots of a PACKET_MMAP TX ring,
+// so a frame costs one memcpy per packet and a single system call
+#[derive(Clone)]
+pub struct RingSocketSender {
+    ring: Arc<Mutex<Reconnecting<TxRing>>>,
+    core: SenderCore,
+}
+
+impl RingSocketSender {
+    pub fn new(interface_name: &str, settings: SenderSettings) -> Result<Self, SenderError> {
+        let src_mac = interface_mac(interface_name)?;
+        let ring = Reconnecting::new(interface_name, TxRing::open)?;
+        Ok(Self {
+            ring: Arc::new(Mutex::new(ring)),
+            core: SenderCore::new(src_mac, settings),
+        })
+    }
+}
+
+impl LinsnSocket for RingSocketSender {
+    fn send_damaged(
+        &self,
+        image: &[Rgb<u8>],
+        dst_mac: MacAddr,
+        damage: &Damage,
+    ) -> Result<(), SenderError> {
+        let encoder = self.core.encode(image, dst_mac, damage)?;
+        let mut ring = self.ring.lock().expect("Failed to acquire lock on ring");
+        let result = ring.get()?.send_packets(encoder.packets());
+        result.map_err(|e| ring.fail(e))
+    }
+}
+
+// Records every Ethernet frame into a pcap file instead of putting it on the wire.
+// Needs neither a network interface nor CAP_NET_RAW.
+#[derive(Clone)]
+pub struct PcapFileSender {
+    writer: Arc<Mutex<PcapWriter<BufWriter<File>>>>,
+    core: SenderCore,
+}
+
+impl PcapFileSender {
+    pub fn new<P: AsRef<Path>>(path: P, settings: SenderSettings) -> Result<Self, SenderError> {
+        let writer = PcapWriter::create(path)?;
+        Ok(Self {
+            writer: Arc::new(Mutex::new(writer)),
+            core: SenderCore::new(MacAddr::broadcast(), settings),
+        })
+    }
+}
+
+impl LinsnSocket for PcapFileSender {
+    fn send_damaged(
+        &self,
+        image: &[Rgb<u8>],
+        dst_mac: MacAddr,
+        damage: &Damage,
+    ) -> Result<(), SenderError> {
+        let encoder = self.core.encode(image, dst_mac, damage)?;
+        let mut writer = self
+            .writer
+            .lock()
+            .expect("Failed to acquire lock on pcap writer");
+        for packet in encoder.packets() {
+            writer.write_frame(SystemTime::now(), packet)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// Hands every Ethernet frame to an in-memory channel, e.g. for a VirtualReceiver
+#[derive(Clone)]
+pub struct ChannelSender {
+    tx: std::sync::mpsc::Sender<Vec<u8>>,
+    core: SenderCore,
+}
+
+impl ChannelSender {
+    pub fn new(tx: std::sync::mpsc::Sender<Vec<u8>>, settings: SenderSettings) -> Self {
+        Self {
+            tx,
+            core: SenderCore::new(MacAddr::broadcast(), settings),
+        }
+    }
+}
+
+impl LinsnSocket for ChannelSender {
+    fn send_damaged(
+        &self,
+        image: &[Rgb<u8>],
+        dst_mac: MacAddr,
+        damage: &Damage,
+    ) -> Result<(), SenderError> {
+        let encoder = self.core.encode(image, dst_mac, damage)?;
+        for packet in encoder.packets() {
+            self.tx
+                .send(packet.to_vec())
+                .map_err(|_| SenderError::ChannelClosed)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    static OPENS: AtomicU32 = AtomicU32::new(0);
+    static UNPLUGGED: AtomicBool = AtomicBool::new(false);
+
+    fn open_fake(interface_name: &str) -> Result<u32, SenderError> {
+        if UNPLUGGED.load(Ordering::Relaxed) {
+            return Err(SenderError::InterfaceNotFound(interface_name.to_string()));
+        }
+        Ok(OPENS.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    #[test]
+    fn test_reconnects_after_link_error() {
+        let mut link = Reconnecting::new("eth0", open_fake).unwrap();
+        assert_eq!(*link.get().unwrap(), 1);
+
+        // Transient errors keep the connection
+        link.fail(io::Error::from_raw_os_error(libc::ENOBUFS));
+        assert_eq!(*link.get().unwrap(), 1);
+
+        // The NIC is gone, reopening fails until it is back
+        UNPLUGGED.store(true, Ordering::Relaxed);
+        link.fail(io::Error::from_raw_os_error(libc::ENETDOWN));
+        assert!(matches!(link.get(), Err(SenderError::InterfaceNotFound(_))));
+        UNPLUGGED.store(false, Ordering::Relaxed);
+        assert!(matches!(link.get(), Err(SenderError::LinkDown(_))));
+
+        link.last_attempt = Some(Instant::now() - RECONNECT_INTERVAL);
+        assert_eq!(*link.get().unwrap(), 2);
     }
 }
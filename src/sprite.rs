@@ -1,57 +1,324 @@
 use std::{
-    fs,
+    cmp::Ordering,
+    fmt, fs, io,
+    path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 
 use image::DynamicImage;
-use rand::{rngs::{self, ThreadRng}, Rng};
+use rand::{rngs::{StdRng, ThreadRng}, Rng, SeedableRng};
+use serde::Deserialize;
 
 use crate::primitives::Panel;
 
-pub fn load_image_directory(dir: &str) -> Vec<DynamicImage> {
-    let mut images_data = Vec::new();
+// Optional file next to the frames of an animation
+pub const MANIFEST_FILE: &str = "frames.toml";
 
-    // Iterate over all entries in the folder.
-    for entry in fs::read_dir(dir).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
+#[derive(Debug)]
+pub enum AssetError {
+    Io(PathBuf, io::Error),
+    Image(PathBuf, image::ImageError),
+    Manifest(PathBuf, toml::de::Error),
+    Sheet(PathBuf, serde_json::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetError::Io(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            AssetError::Image(path, e) => write!(f, "failed to load {}: {}", path.display(), e),
+            AssetError::Manifest(path, e) => write!(f, "failed to parse {}: {}", path.display(), e),
+            AssetError::Sheet(path, e) => write!(f, "failed to parse {}: {}", path.display(), e),
+            AssetError::Invalid(reason) => write!(f, "invalid asset: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AssetError {}
+
+// A manifest picks and orders the frames of its directory, without one every
+// PNG is used in natural order:
+//
+//     duration_ms = 100
+//
+//     [[frame]]
+//     file = "dragon_frame001.png"
+//     name = "wings_up"
+//     duration_ms = 250
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    // Applies to every frame without its own duration
+    duration_ms: Option<u64>,
+    #[serde(rename = "frame")]
+    frames: Vec<ManifestFrame>,
+}
 
-        // Check the file extension (case-insensitively) to see if it’s a PNG.
-        if path
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .map(|s| s.eq_ignore_ascii_case("png"))
-            .unwrap_or(false)
-        {
-            // Open the image file.
-            let img = image::open(&path).unwrap();
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestFrame {
+    file: PathBuf,
+    name: Option<String>,
+    duration_ms: Option<u64>,
+}
+
+// One frame of an animation. Frames without a duration run at the framerate of
+// the sprite.
+#[derive(Debug, Clone)]
+pub struct SpriteFrame {
+    pub name: String,
+    pub image: DynamicImage,
+    pub duration: Option<Duration>,
+}
 
-            images_data.push(img);
+// Compares digit runs by their value, so frame2 sorts before frame10
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(ca), Some(cb)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        if ca.is_ascii_digit() && cb.is_ascii_digit() {
+            let digits_a = a.len() - a.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            let digits_b = b.len() - b.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            let number_a = a[..digits_a].trim_start_matches('0');
+            let number_b = b[..digits_b].trim_start_matches('0');
+            let ordering = number_a
+                .len()
+                .cmp(&number_b.len())
+                .then_with(|| number_a.cmp(number_b))
+                // 01 and 1 only tie if everything else does
+                .then_with(|| digits_a.cmp(&digits_b));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            a = &a[digits_a..];
+            b = &b[digits_b..];
+        } else {
+            if ca != cb {
+                return ca.cmp(&cb);
+            }
+            a = &a[ca.len_utf8()..];
+            b = &b[cb.len_utf8()..];
         }
     }
-    println!("Loaded {} PNG images from {}", images_data.len(), dir);
+}
 
-    images_data
+fn is_png(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
 }
 
-#[derive(Debug)]
+fn open_image(path: &Path) -> Result<DynamicImage, AssetError> {
+    image::open(path).map_err(|e| AssetError::Image(path.to_path_buf(), e))
+}
+
+// Loads the frames of an animation directory, in manifest order if there is a
+// frames.toml and in natural filename order otherwise
+pub fn load_frame_directory<P: AsRef<Path>>(dir: P) -> Result<Vec<SpriteFrame>, AssetError> {
+    let dir = dir.as_ref();
+    let manifest_path = dir.join(MANIFEST_FILE);
+    let frames = if manifest_path.is_file() {
+        let content = fs::read_to_string(&manifest_path)
+            .map_err(|e| AssetError::Io(manifest_path.clone(), e))?;
+        let manifest: Manifest = toml::from_str(&content)
+            .map_err(|e| AssetError::Manifest(manifest_path.clone(), e))?;
+        manifest
+            .frames
+            .into_iter()
+            .map(|frame| {
+                let path = dir.join(&frame.file);
+                Ok(SpriteFrame {
+                    name: frame.name.unwrap_or_else(|| frame_name(&path)),
+                    image: open_image(&path)?,
+                    duration: frame
+                        .duration_ms
+                        .or(manifest.duration_ms)
+                        .map(Duration::from_millis),
+                })
+            })
+            .collect::<Result<Vec<_>, AssetError>>()?
+    } else {
+        let entries = fs::read_dir(dir).map_err(|e| AssetError::Io(dir.to_path_buf(), e))?;
+        let mut paths = vec![];
+        for entry in entries {
+            let path = entry.map_err(|e| AssetError::Io(dir.to_path_buf(), e))?.path();
+            if is_png(&path) {
+                paths.push(path);
+            }
+        }
+        paths.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+        paths
+            .iter()
+            .map(|path| {
+                Ok(SpriteFrame {
+                    name: frame_name(path),
+                    image: open_image(path)?,
+                    duration: None,
+                })
+            })
+            .collect::<Result<Vec<_>, AssetError>>()?
+    };
+
+    if frames.is_empty() {
+        return Err(AssetError::Invalid(format!("{} has no frames", dir.display())));
+    }
+    println!("Loaded {} PNG images from {}", frames.len(), dir.display());
+    Ok(frames)
+}
+
+pub fn load_image_directory<P: AsRef<Path>>(dir: P) -> Result<Vec<DynamicImage>, AssetError> {
+    let frames = load_frame_directory(dir)?;
+    Ok(frames.into_iter().map(|frame| frame.image).collect())
+}
+
+fn frame_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum LoopMode {
-    Loop,     // Always loop from the beginning.
-    PingPong, // Loop forward then backward.
+    #[default]
+    Loop,        // Always loop from the beginning.
+    PingPong,    // Loop forward then backward.
+    Once,        // Play once and hold the last frame.
+    Reverse,     // Always loop from the end.
+    Random,      // Show a random other frame every time.
+    LoopN(u32),  // Loop a number of times and hold the last frame.
+}
+
+// A named part of the frames of a sprite, like the tags of an Aseprite sheet
+#[derive(Debug, Clone)]
+pub struct Animation {
+    pub name: String,
+    // Indices into the frames of the sprite, in playback order
+    pub frames: Vec<usize>,
+    pub loop_mode: LoopMode,
+    // Passes before the animation holds its last frame, None plays forever
+    pub plays: Option<u32>,
+}
+
+// Speed curve of a path segment, maps the elapsed part of the segment to the
+// travelled part
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    CubicIn,
+    CubicOut,
+    CubicInOut,
+    Bounce,  // Bounces off the end point like a dropped ball.
+    Elastic, // Overshoots the end point and swings back.
+}
+
+impl Easing {
+    pub fn apply(self, t: f64) -> f64 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t).powi(2),
+            Easing::EaseInOut if t < 0.5 => 2.0 * t * t,
+            Easing::EaseInOut => 1.0 - (-2.0 * t + 2.0).powi(2) / 2.0,
+            Easing::CubicIn => t.powi(3),
+            Easing::CubicOut => 1.0 - (1.0 - t).powi(3),
+            Easing::CubicInOut if t < 0.5 => 4.0 * t.powi(3),
+            Easing::CubicInOut => 1.0 - (-2.0 * t + 2.0).powi(3) / 2.0,
+            Easing::Bounce => {
+                const N: f64 = 7.5625;
+                const D: f64 = 2.75;
+                if t < 1.0 / D {
+                    N * t * t
+                } else if t < 2.0 / D {
+                    let t = t - 1.5 / D;
+                    N * t * t + 0.75
+                } else if t < 2.5 / D {
+                    let t = t - 2.25 / D;
+                    N * t * t + 0.9375
+                } else {
+                    let t = t - 2.625 / D;
+                    N * t * t + 0.984375
+                }
+            }
+            Easing::Elastic if t == 0.0 || t == 1.0 => t,
+            Easing::Elastic => {
+                2f64.powf(-10.0 * t) * ((t * 10.0 - 0.75) * (2.0 * std::f64::consts::PI / 3.0)).sin() + 1.0
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathCurve {
+    #[default]
+    Linear,     // Straight lines between the points.
+    CatmullRom, // A smooth curve through all points.
+    Bezier,     // Cubic Bezier segments, two control points between every pair of points.
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathPlayback {
+    #[default]
+    Once,     // Stop at the last point and report the path as finished.
+    Loop,     // Jump back to the first point.
+    PingPong, // Travel back to the first point, facing the other way.
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathPoint {
+    pub x: i32,
+    pub y: i32,
+    // Time to get here from the previous point, ignored for the first one
+    pub duration_ms: u64,
+    pub flip: bool,
+    // Speed curve of the segment that ends here
+    pub easing: Easing,
+}
+
+impl PathPoint {
+    pub fn new(x: i32, y: i32, duration_ms: u64, flip: bool) -> Self {
+        PathPoint {
+            x,
+            y,
+            duration_ms,
+            flip,
+            easing: Easing::Linear,
+        }
+    }
+
+    pub fn with_easing(mut self, easing: Easing) -> Self {
+        self.easing = easing;
+        self
+    }
 }
 
 #[derive(Clone)]
 
 pub struct AnimatedPath {
-    points: Vec<(i32, i32, u64, bool)>,
+    points: Vec<PathPoint>,
+    curve: PathCurve,
+    playback: PathPlayback,
     start: Option<Instant>,
     finished: bool,
 }
 
 impl AnimatedPath {
-    pub fn new(points: Vec<(i32, i32, u64, bool)>) -> Self {
+    pub fn new(points: Vec<PathPoint>) -> Self {
         AnimatedPath{
             points,
+            curve: PathCurve::Linear,
+            playback: PathPlayback::Once,
             start: None,
             finished: false,
         }
@@ -65,23 +332,132 @@ impl AnimatedPath {
         if left {
             std::mem::swap(&mut start, &mut end);
         }
-        let dragon_animation: AnimatedPath = AnimatedPath::new(vec![(start, height, 0, left), (end, height,duration, left)]);
+        let dragon_animation: AnimatedPath = AnimatedPath::new(vec![
+            PathPoint::new(start, height, 0, left),
+            PathPoint::new(end, height, duration, left),
+        ]);
         dragon_animation
     }
 
-    pub fn reset(&mut self) {
-        self.start = None;
-        self.finished = false;
+    pub fn with_curve(mut self, curve: PathCurve) -> Self {
+        self.curve = curve;
+        self
+    }
+
+    pub fn with_playback(mut self, playback: PathPlayback) -> Self {
+        self.playback = playback;
+        self
+    }
+
+    // Uses the same speed curve for every segment
+    pub fn with_easing(mut self, easing: Easing) -> Self {
+        for point in &mut self.points {
+            point.easing = easing;
+        }
+        self
+    }
+
+    // Indices of the points the path passes through, Bezier paths steer with the
+    // points in between
+    fn anchors(&self) -> Vec<usize> {
+        match self.curve {
+            PathCurve::Bezier => (0..self.points.len()).step_by(3).collect(),
+            _ => (0..self.points.len()).collect(),
+        }
+    }
+
+    // Position and facing after the given time, None once a path played once is
+    // over
+    pub fn position_at(&self, elapsed: Duration) -> Option<(i32, i32, bool)> {
+        let anchors = self.anchors();
+        let total: u64 = anchors.iter().skip(1).map(|&i| self.points[i].duration_ms).sum();
+        let last = self.points[*anchors.last()?];
+        let mut time = elapsed.as_secs_f64() * 1000.0;
+        let mut backwards = false;
+        match self.playback {
+            _ if total == 0 => return (self.playback != PathPlayback::Once).then_some((last.x, last.y, last.flip)),
+            PathPlayback::Once if time >= total as f64 => return None,
+            PathPlayback::Once => {}
+            PathPlayback::Loop => time %= total as f64,
+            PathPlayback::PingPong => {
+                time %= 2.0 * total as f64;
+                if time >= total as f64 {
+                    time = 2.0 * total as f64 - time;
+                    backwards = true;
+                }
+            }
+        }
+
+        for segment in anchors.windows(2) {
+            let (from, to) = (segment[0], segment[1]);
+            let duration = self.points[to].duration_ms as f64;
+            if time > duration {
+                time -= duration;
+                continue;
+            }
+            let factor = if duration > 0.0 {
+                self.points[to].easing.apply(time / duration)
+            } else {
+                1.0
+            };
+            let (x, y) = self.interpolate(from, to, factor);
+            return Some((x.round() as i32, y.round() as i32, self.points[to].flip != backwards));
+        }
+        Some((last.x, last.y, last.flip != backwards))
+    }
+
+    fn interpolate(&self, from: usize, to: usize, t: f64) -> (f64, f64) {
+        let point = |i: usize| (self.points[i].x as f64, self.points[i].y as f64);
+        let (p1, p2) = (point(from), point(to));
+        match self.curve {
+            PathCurve::Linear => (p1.0 + (p2.0 - p1.0) * t, p1.1 + (p2.1 - p1.1) * t),
+            PathCurve::CatmullRom => {
+                // The neighbours set the tangents, the ends reuse their own point
+                let p0 = point(from.saturating_sub(1));
+                let p3 = point((to + 1).min(self.points.len() - 1));
+                let spline = |p0: f64, p1: f64, p2: f64, p3: f64| {
+                    0.5 * (2.0 * p1
+                        + (p2 - p0) * t
+                        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t * t
+                        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t * t * t)
+                };
+                (spline(p0.0, p1.0, p2.0, p3.0), spline(p0.1, p1.1, p2.1, p3.1))
+            }
+            PathCurve::Bezier => {
+                let (c1, c2) = (point(from + 1), point(from + 2));
+                let u = 1.0 - t;
+                let bezier = |p0: f64, c1: f64, c2: f64, p3: f64| {
+                    u * u * u * p0 + 3.0 * u * u * t * c1 + 3.0 * u * t * t * c2 + t * t * t * p3
+                };
+                (bezier(p1.0, c1.0, c2.0, p2.0), bezier(p1.1, c1.1, c2.1, p2.1))
+            }
+        }
     }
 }
 
+type FinishedCallback = Box<dyn FnMut(&mut AnimatedSprite) + Send>;
+
 pub struct AnimatedSprite {
     images: Vec<DynamicImage>,
-    frame_duration: Duration,
+    frame_durations: Vec<Duration>,
+    // Names of the frames of a directory or sheet, empty for plain images
+    frame_names: Vec<String>,
+    animations: Vec<Animation>,
+    // Frames of the playing animation, current_frame indexes into it
+    sequence: Vec<usize>,
     last_update: Instant,
     current_frame: usize,
     loop_mode: LoopMode,
     _direction: i32,
+    // Passes through the frames before the sprite holds its last frame, None
+    // plays forever
+    plays: Option<u32>,
+    passes: u32,
+    // Frames shown in the current pass, for LoopMode::Random
+    steps: usize,
+    on_finished: Option<FinishedCallback>,
+    // Picks the frames of LoopMode::Random
+    rng: StdRng,
     scale: f32,
     flip: bool,
     path: Option<AnimatedPath>,
@@ -90,25 +466,121 @@ pub struct AnimatedSprite {
 
 impl AnimatedSprite {
     pub fn new(images: Vec<DynamicImage>, framerate: f32, loop_mode: LoopMode, scale: f32) -> Self {
-        AnimatedSprite {
+        let frame_duration = Duration::from_millis((1000.0 / framerate) as u64);
+        let mut sprite = AnimatedSprite {
+            frame_durations: vec![frame_duration; images.len()],
+            frame_names: vec![],
+            animations: vec![],
+            sequence: (0..images.len()).collect(),
             images,
             position: (0, 0),
-            frame_duration: Duration::from_millis((1000.0 / framerate) as u64),
             last_update: Instant::now(),
             current_frame: 0,
             loop_mode,
             _direction: 1,
+            plays: None,
+            passes: 0,
+            steps: 0,
+            on_finished: None,
+            rng: StdRng::from_entropy(),
             scale,
             flip: false,
             path: None,
+        };
+        sprite.restart();
+        sprite
+    }
+
+    // Frames with their own duration ignore the framerate
+    pub fn from_frames(frames: Vec<SpriteFrame>, framerate: f32, loop_mode: LoopMode, scale: f32) -> Self {
+        let durations: Vec<Option<Duration>> = frames.iter().map(|frame| frame.duration).collect();
+        let names = frames.iter().map(|frame| frame.name.clone()).collect();
+        let mut sprite = AnimatedSprite::new(
+            frames.into_iter().map(|frame| frame.image).collect(),
+            framerate,
+            loop_mode,
+            scale,
+        );
+        sprite.frame_names = names;
+        for (duration, frame_duration) in durations.into_iter().zip(&mut sprite.frame_durations) {
+            if let Some(duration) = duration {
+                *frame_duration = duration;
+            }
         }
+        sprite
     }
 
-    pub fn set_scale(&mut self, scale: f32) {
-        if scale <= 0.0 {
-            return;
+    // Makes LoopMode::Random repeatable
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    pub fn set_animations(&mut self, animations: Vec<Animation>) {
+        self.animations = animations;
+    }
+
+    pub fn animations(&self) -> impl Iterator<Item = &str> {
+        self.animations.iter().map(|animation| animation.name.as_str())
+    }
+
+    pub fn frame_index(&self, name: &str) -> Option<usize> {
+        self.frame_names.iter().position(|frame| frame == name)
+    }
+
+    // Switches to a named animation or holds the frame of that name, returns
+    // false if there is neither
+    pub fn play(&mut self, name: &str) -> bool {
+        if let Some(animation) = self.animations.iter().find(|animation| animation.name == name) {
+            self.sequence = animation.frames.clone();
+            self.loop_mode = animation.loop_mode;
+            self.plays = animation.plays;
+        } else if let Some(frame) = self.frame_index(name) {
+            self.sequence = vec![frame];
+            self.loop_mode = LoopMode::Loop;
+            self.plays = None;
+        } else {
+            return false;
+        }
+        self.restart();
+        true
+    }
+
+    // Limits the passes of loop modes without a count of their own, like the loop
+    // count of a GIF
+    pub fn set_plays(&mut self, plays: Option<u32>) {
+        self.plays = plays;
+        self.restart();
+    }
+
+    // Called once the sprite reaches the end of its last pass. The callback may
+    // play the next animation, which chains them without polling is_finished.
+    pub fn set_on_finished<F: FnMut(&mut AnimatedSprite) + Send + 'static>(&mut self, on_finished: F) {
+        self.on_finished = Some(Box::new(on_finished));
+    }
+
+    fn restart(&mut self) {
+        self.current_frame = match self.loop_mode {
+            LoopMode::Reverse => self.sequence.len().saturating_sub(1),
+            _ => 0,
+        };
+        self._direction = 1;
+        self.passes = 0;
+        self.steps = 0;
+        self.last_update = Instant::now();
+    }
+
+    fn pass_limit(&self) -> Option<u32> {
+        match self.loop_mode {
+            LoopMode::Once => Some(1),
+            LoopMode::LoopN(count) => Some(count),
+            _ => self.plays,
         }
-        self.scale = scale
+    }
+
+    // True while the sprite holds the last frame of its last pass
+    pub fn is_finished(&self) -> bool {
+        self.pass_limit().is_some_and(|limit| self.passes >= limit)
     }
 
     pub fn flip(&mut self) {
@@ -128,92 +600,251 @@ impl AnimatedSprite {
         self.draw(panel);
     }
 
-    pub fn reset_animation(&mut self) {
-        println!("Restarting animation");
-        self.path.as_mut().unwrap().reset();
-    }
 
     pub fn has_finished(&mut self)  -> bool{
         self.path.as_ref().unwrap().finished
     }
 
     pub fn animate(&mut self, panel: &mut Panel) {
-        if self.path.is_none() {
+        let Some(path) = self.path.as_mut() else {
             return;
-        }
-        if (self.has_finished()) {
+        };
+        if path.finished {
             self.draw(panel);
             return;
         }
-        let mut new_position: Option<(i32, i32, bool)> = None;
-        {
-            let path = &mut self.path.as_mut().unwrap();
-            if path.start.is_none() {
-                println!("Starting animation");
-                path.start = Some(Instant::now())
-            }
-
-            let mut time = Instant::now()- path.start.unwrap() ;
-            path.finished = true;
-            for i in 0..path.points.len() - 1 {
-                let current_point = path.points[i];
-                let next_point = path.points[i+1];
-                let target_duration = Duration::from_millis( next_point.2);
-                if time>target_duration {
-                    time -= target_duration;
-                    continue;
-                }
-                path.finished = false;
-                // println!("Animating from point {} to {}: {}/{} -> {}/{}", i , i+1, current_point.0, current_point.1, next_point.0, next_point.1);
-
-                let factor = time.as_millis() as f64/ next_point.2 as f64;
-
-                // println!("Time: {}, Factor: {} Start: {:?}", time.as_millis(), factor, path.start.unwrap());
-
-                let new_x = (current_point.0 as f64 * (1.0 -factor) + next_point.0 as f64* (factor));
-                let new_y = (current_point.1 as f64 * (1.0 - factor) + next_point.1 as f64* (factor));
-                let flipped = next_point.3;
-                new_position = Some((new_x as i32, new_y as i32, flipped));
-                break;
+        let start = *path.start.get_or_insert_with(|| {
+            println!("Starting animation");
+            Instant::now()
+        });
+        match path.position_at(start.elapsed()) {
+            Some((x, y, flip)) => {
+                self.flip = flip;
+                self.draw_at(panel, x, y);
             }
-        }
-        if let Some(pos) = new_position{
-            self.flip = pos.2;
-            self.draw_at(panel, pos.0, pos.1);
+            None => path.finished = true,
         }
     }
 
     pub fn draw(&mut self, panel: &mut Panel) {
         let now = Instant::now();
         // Check if enough time has passed to advance the frame.
-        if now.duration_since(self.last_update) >= self.frame_duration {
+        let frame = self.sequence[self.current_frame];
+        if now.duration_since(self.last_update) >= self.frame_durations[frame] && !self.is_finished() {
             self.last_update = now;
-            match self.loop_mode {
-                LoopMode::Loop => {
-                    // Move to the next frame, wrapping back to 0.
-                    self.current_frame = (self.current_frame + 1) % self.images.len();
+            self.advance();
+            if self.is_finished() {
+                if let Some(mut on_finished) = self.on_finished.take() {
+                    on_finished(self);
+                    // Unless the callback installed another one
+                    self.on_finished.get_or_insert(on_finished);
                 }
-                LoopMode::PingPong => {
-                    // Calculate the next frame index.
-                    let next_frame = self.current_frame as i32 + self._direction;
-                    if next_frame < 0 || next_frame >= self.images.len() as i32 {
-                        // Reverse direction when hitting the edges.
-                        self._direction = -self._direction;
-                        self.current_frame = (self.current_frame as i32 + self._direction) as usize;
-                    } else {
-                        self.current_frame = next_frame as usize;
-                    }
+            }
+        }
+        self.draw_current(panel);
+    }
+
+    // Moves to the next frame, or stays on the current one if that ends the last pass
+    fn advance(&mut self) {
+        let len = self.sequence.len();
+        let (next_frame, pass_done) = match self.loop_mode {
+            LoopMode::Loop | LoopMode::Once | LoopMode::LoopN(_) => {
+                // Move to the next frame, wrapping back to 0.
+                ((self.current_frame + 1) % len, self.current_frame + 1 == len)
+            }
+            LoopMode::Reverse => ((self.current_frame + len - 1) % len, self.current_frame == 0),
+            LoopMode::PingPong if len == 1 => (0, true),
+            LoopMode::PingPong => {
+                // Calculate the next frame index.
+                let mut next_frame = self.current_frame as i32 + self._direction;
+                // A pass ends back at the first frame
+                let pass_done = next_frame < 0;
+                if next_frame < 0 || next_frame >= len as i32 {
+                    // Reverse direction when hitting the edges.
+                    self._direction = -self._direction;
+                    next_frame = self.current_frame as i32 + self._direction;
                 }
+                (next_frame as usize, pass_done)
+            }
+            LoopMode::Random => {
+                self.steps += 1;
+                let next_frame = match len {
+                    1 => 0,
+                    // Any frame but the current one
+                    _ => (self.current_frame + self.rng.gen_range(1..len)) % len,
+                };
+                (next_frame, self.steps.is_multiple_of(len))
+            }
+        };
+        if pass_done {
+            self.passes += 1;
+            if self.is_finished() {
+                return;
             }
         }
+        self.current_frame = next_frame;
+    }
+
+    fn draw_current(&self, panel: &mut Panel) {
         // Draw the current image using the provided drawing function.
         panel.draw_image(
             self.position.0,
             self.position.1,
-            &self.images[self.current_frame],
+            &self.images[self.sequence[self.current_frame]],
             self.scale,
             self.flip,
             false,
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_natural_order() {
+        let mut names = vec!["frame10", "frame2", "frame001", "frame1", "b", "a10b", "a9c"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["a9c", "a10b", "b", "frame1", "frame001", "frame2", "frame10"]);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let dir = TempDir::new("frames");
+        for name in ["frame2.png", "frame10.png"] {
+            image::RgbImage::new(1, 1).save(dir.join(name)).unwrap();
+        }
+        let names = |frames: Vec<SpriteFrame>| frames.into_iter().map(|f| f.name).collect::<Vec<_>>();
+        assert_eq!(names(load_frame_directory(&dir).unwrap()), vec!["frame2", "frame10"]);
+
+        fs::write(
+            dir.join(MANIFEST_FILE),
+            "duration_ms = 100\n[[frame]]\nfile = \"frame10.png\"\nname = \"up\"\nduration_ms = 250\n[[frame]]\nfile = \"frame2.png\"",
+        )
+        .unwrap();
+        let frames = load_frame_directory(&dir).unwrap();
+        assert_eq!(frames[0].duration, Some(Duration::from_millis(250)));
+        assert_eq!(frames[1].duration, Some(Duration::from_millis(100)));
+        assert_eq!(names(frames), vec!["up", "frame2"]);
+
+        // Named frames can be held like an animation
+        let mut sprite = AnimatedSprite::from_frames(load_frame_directory(&dir).unwrap(), 1.0, LoopMode::Loop, 1.0);
+        assert_eq!(sprite.frame_index("frame2"), Some(1));
+        assert!(sprite.play("up"));
+        assert_eq!(play(&mut sprite, 3), vec![0, 0, 0]);
+        assert!(!sprite.play("down"));
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(load_frame_directory(&dir), Err(AssetError::Io(..))));
+    }
+
+    fn sprite(frames: usize, loop_mode: LoopMode) -> AnimatedSprite {
+        // Zero frame durations advance on every draw, the seed keeps
+        // LoopMode::Random the same on every run
+        AnimatedSprite::new(vec![DynamicImage::new_rgba8(1, 1); frames], 2000.0, loop_mode, 1.0).with_seed(7)
+    }
+
+    fn play(sprite: &mut AnimatedSprite, steps: usize) -> Vec<usize> {
+        let geometry = crate::linsn::FrameGeometry::new(4, 4, 0);
+        let mut panel = Panel::with_geometry(4, 4, geometry, false, false);
+        (0..steps)
+            .map(|_| {
+                sprite.draw(&mut panel);
+                sprite.sequence[sprite.current_frame]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_loop_modes() {
+        assert_eq!(play(&mut sprite(3, LoopMode::Loop), 4), vec![1, 2, 0, 1]);
+        assert_eq!(play(&mut sprite(3, LoopMode::PingPong), 5), vec![1, 2, 1, 0, 1]);
+        assert_eq!(play(&mut sprite(3, LoopMode::Reverse), 4), vec![1, 0, 2, 1]);
+
+        let mut once = sprite(3, LoopMode::Once);
+        assert_eq!(play(&mut once, 4), vec![1, 2, 2, 2]);
+        assert!(once.is_finished());
+        let mut twice = sprite(2, LoopMode::LoopN(2));
+        assert_eq!(play(&mut twice, 4), vec![1, 0, 1, 1]);
+
+        let mut random = sprite(3, LoopMode::Random);
+        let frames = play(&mut random, 6);
+        assert!(frames.windows(2).all(|pair| pair[0] != pair[1]));
+
+        // The loop count of a file stops the other modes too
+        let mut ping_pong = sprite(2, LoopMode::PingPong);
+        ping_pong.set_plays(Some(1));
+        assert_eq!(play(&mut ping_pong, 3), vec![1, 0, 0]);
+    }
+
+    #[test]
+    fn test_chained_animations() {
+        let mut dragon = sprite(4, LoopMode::Loop);
+        let animation = |name: &str, frames: Vec<usize>, plays| Animation {
+            name: name.to_string(),
+            frames,
+            loop_mode: LoopMode::Loop,
+            plays,
+        };
+        dragon.set_animations(vec![animation("land", vec![0, 1], Some(1)), animation("idle", vec![2, 3], None)]);
+        dragon.set_on_finished(|sprite| {
+            sprite.play("idle");
+        });
+        assert!(dragon.play("land"));
+        assert_eq!(play(&mut dragon, 5), vec![1, 2, 3, 2, 3]);
+        assert!(!dragon.play("fly"));
+    }
+
+    #[test]
+    fn test_easing() {
+        let easings = [
+            Easing::Linear,
+            Easing::EaseIn,
+            Easing::EaseOut,
+            Easing::EaseInOut,
+            Easing::CubicIn,
+            Easing::CubicOut,
+            Easing::CubicInOut,
+            Easing::Bounce,
+            Easing::Elastic,
+        ];
+        for easing in easings {
+            assert!(easing.apply(0.0).abs() < 1e-9, "{:?}", easing);
+            assert!((easing.apply(1.0) - 1.0).abs() < 1e-9, "{:?}", easing);
+        }
+        assert!(Easing::EaseIn.apply(0.5) < 0.5);
+        assert!(Easing::EaseOut.apply(0.5) > 0.5);
+        assert!(Easing::Elastic.apply(0.2) > 1.0);
+    }
+
+    #[test]
+    fn test_path_playback() {
+        let at = |ms| Duration::from_millis(ms);
+        let points = vec![PathPoint::new(0, 0, 0, false), PathPoint::new(100, 50, 1000, false)];
+        let path = AnimatedPath::new(points.clone());
+        assert_eq!(path.position_at(at(500)), Some((50, 25, false)));
+        assert_eq!(path.position_at(at(1000)), None);
+
+        let path = AnimatedPath::new(points.clone()).with_playback(PathPlayback::Loop);
+        assert_eq!(path.position_at(at(1250)), Some((25, 13, false)));
+        let path = AnimatedPath::new(points).with_playback(PathPlayback::PingPong);
+        assert_eq!(path.position_at(at(1250)), Some((75, 38, true)));
+
+        // Curves pass through their points
+        let points = vec![
+            PathPoint::new(0, 0, 0, false),
+            PathPoint::new(10, 20, 1000, false),
+            PathPoint::new(20, 0, 1000, false),
+            PathPoint::new(30, 20, 1000, false),
+        ];
+        let spline = AnimatedPath::new(points.clone()).with_curve(PathCurve::CatmullRom);
+        assert_eq!(spline.position_at(at(1000)), Some((10, 20, false)));
+        assert_eq!(spline.position_at(at(2000)), Some((20, 0, false)));
+        let bezier = AnimatedPath::new(points).with_curve(PathCurve::Bezier);
+        assert_eq!(bezier.position_at(at(0)), Some((0, 0, false)));
+        assert_eq!(bezier.position_at(at(500)), Some((15, 10, false)));
+    }
+}
+
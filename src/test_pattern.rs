@@ -0,0 +1,61 @@
+use clap::ValueEnum;
+use image::Rgba;
+
+use crate::primitives::Panel;
+
+// Static images for commissioning a wall
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum TestPattern {
+    // Eight vertical color bars, shows swapped channels at a glance
+    Bars,
+    // Lines every 16 pixels and a red border, shows misplaced cabinets
+    Grid,
+    // Red, green, blue and white ramps, shows the gamma calibration
+    Gradient,
+    // Everything at full white, for power and brightness checks
+    White,
+}
+
+const BAR_COLORS: [[u8; 3]; 8] = [
+    [0xFF, 0xFF, 0xFF],
+    [0xFF, 0xFF, 0x00],
+    [0x00, 0xFF, 0xFF],
+    [0x00, 0xFF, 0x00],
+    [0xFF, 0x00, 0xFF],
+    [0xFF, 0x00, 0x00],
+    [0x00, 0x00, 0xFF],
+    [0x00, 0x00, 0x00],
+];
+
+const GRID_SPACING: usize = 16;
+
+pub fn draw_test_pattern(panel: &mut Panel, pattern: TestPattern) {
+    let (width, height) = (panel.width, panel.height);
+    for y in 0..height {
+        for x in 0..width {
+            let [r, g, b] = match pattern {
+                TestPattern::Bars => BAR_COLORS[x * BAR_COLORS.len() / width],
+                TestPattern::Grid => {
+                    if x == 0 || y == 0 || x == width - 1 || y == height - 1 {
+                        [0xFF, 0x00, 0x00]
+                    } else if x % GRID_SPACING == 0 || y % GRID_SPACING == 0 {
+                        [0xFF, 0xFF, 0xFF]
+                    } else {
+                        [0x00, 0x00, 0x00]
+                    }
+                }
+                TestPattern::Gradient => {
+                    let level = (x * 0xFF / (width - 1).max(1)) as u8;
+                    match y * 4 / height {
+                        0 => [level, 0, 0],
+                        1 => [0, level, 0],
+                        2 => [0, 0, level],
+                        _ => [level, level, level],
+                    }
+                }
+                TestPattern::White => [0xFF, 0xFF, 0xFF],
+            };
+            panel.set_pixel(x as i32, y as i32, Rgba([r, g, b, 0xFF]));
+        }
+    }
+}
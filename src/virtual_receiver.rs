@@ -0,0 +1,259 @@
+use std::io;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+use image::{Rgb, RgbImage};
+
+use crate::linsn::{
+    bytes_to_pixel, ColorFormat, FrameGeometry, LinsnParseError, LinsnSenderPacket,
+    BYTES_PER_PIXEL, CHUNK_SIZE, ETHERNET_TYPE_SENDER,
+};
+use crate::pcap::PcapReader;
+use crate::socket::{is_link_error, open_receiver, SenderError, RECEIVE_RETRY_INTERVAL};
+
+#[derive(Debug, Clone)]
+pub struct DecodedFrame {
+    pub image: RgbImage,
+    pub missing_chunks: Vec<u32>,
+    pub duplicate_chunks: Vec<u32>,
+}
+
+impl DecodedFrame {
+    pub fn is_complete(&self) -> bool {
+        self.missing_chunks.is_empty()
+    }
+}
+
+// Software stand-in for a receiver card: reassembles 0xAA55 packets into full
+// sender-card frames. A frame is emitted once its last chunk arrives, or when
+// chunk 0 of the next frame shows up first.
+pub struct VirtualReceiver {
+    color_format: ColorFormat,
+    geometry: FrameGeometry,
+    image: RgbImage,
+    received: Vec<bool>,
+    duplicates: Vec<u32>,
+    in_progress: bool,
+}
+
+impl VirtualReceiver {
+    pub fn with_geometry(color_format: ColorFormat, geometry: FrameGeometry) -> Self {
+        Self {
+            color_format,
+            geometry,
+            image: RgbImage::new(geometry.width, geometry.height),
+            received: vec![false; geometry.chunk_count()],
+            duplicates: vec![],
+            in_progress: false,
+        }
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.received.len()
+    }
+
+    pub fn push_packet(&mut self, packet: &LinsnSenderPacket) -> Option<DecodedFrame> {
+        let package_id = packet.header.package_id as usize;
+        if package_id >= self.chunk_count() {
+            return None;
+        }
+
+        let mut finished = None;
+        if package_id == 0 && self.in_progress {
+            finished = self.finish();
+        }
+        self.in_progress = true;
+
+        if self.received[package_id] {
+            self.duplicates.push(package_id as u32);
+        }
+        self.received[package_id] = true;
+
+        let width = self.geometry.width as usize;
+        let first_pixel = package_id * CHUNK_SIZE;
+        let last_pixel = (first_pixel + CHUNK_SIZE).min(self.geometry.pixel_count());
+        for (index, bytes) in packet
+            .payload
+            .chunks_exact(BYTES_PER_PIXEL)
+            .take(last_pixel - first_pixel)
+            .enumerate()
+        {
+            let pixel = first_pixel + index;
+            self.image.put_pixel(
+                (pixel % width) as u32,
+                (pixel / width) as u32,
+                bytes_to_pixel(self.color_format, bytes),
+            );
+        }
+
+        if package_id == self.chunk_count() - 1 {
+            return self.finish();
+        }
+        finished
+    }
+
+    pub fn push_ethernet(&mut self, frame: &[u8]) -> Result<Option<DecodedFrame>, LinsnParseError> {
+        let packet = LinsnSenderPacket::from_ethernet(frame)?;
+        Ok(self.push_packet(&packet))
+    }
+
+    // Emits the frame currently being assembled, even if it is incomplete
+    pub fn finish(&mut self) -> Option<DecodedFrame> {
+        if !self.in_progress {
+            return None;
+        }
+        let missing_chunks = self
+            .received
+            .iter()
+            .enumerate()
+            .filter(|(_, received)| !**received)
+            .map(|(package_id, _)| package_id as u32)
+            .collect();
+        let frame = DecodedFrame {
+            image: self.image.clone(),
+            missing_chunks,
+            duplicate_chunks: std::mem::take(&mut self.duplicates),
+        };
+
+        self.received.fill(false);
+        self.image.pixels_mut().for_each(|p| *p = Rgb([0, 0, 0]));
+        self.in_progress = false;
+        Some(frame)
+    }
+
+    pub fn decode_pcap<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Vec<DecodedFrame>> {
+        let mut frames = vec![];
+        for record in PcapReader::open(path)? {
+            let record = record?;
+            if let Ok(Some(frame)) = self.push_ethernet(&record.data) {
+                frames.push(frame);
+            }
+        }
+        frames.extend(self.finish());
+        Ok(frames)
+    }
+
+    // Decodes the frames of a ChannelSender, returns None once it is dropped
+    pub fn decode_channel(&mut self, rx: &mpsc::Receiver<Vec<u8>>) -> Option<DecodedFrame> {
+        for frame in rx.iter() {
+            if let Ok(Some(decoded)) = self.push_ethernet(&frame) {
+                return Some(decoded);
+            }
+        }
+        self.finish()
+    }
+
+    // Decodes everything arriving on a raw interface, e.g. one end of a veth pair.
+    // The listener thread stops once the returned receiver is dropped.
+    pub fn listen(
+        interface_name: &str,
+        color_format: ColorFormat,
+        geometry: FrameGeometry,
+    ) -> Result<mpsc::Receiver<DecodedFrame>, SenderError> {
+        let mut rx = open_receiver(interface_name, Default::default())?;
+
+        let (frame_tx, frame_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut receiver = VirtualReceiver::with_geometry(color_format, geometry);
+            loop {
+                let frame = match rx.next() {
+                    Ok(frame) => frame,
+                    Err(e) if is_link_error(&e) => {
+                        eprintln!("Stopped listening: {}", e);
+                        return;
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to receive packet: {}", e);
+                        thread::sleep(RECEIVE_RETRY_INTERVAL);
+                        continue;
+                    }
+                };
+                if frame.len() < 14 || frame[12..14] != ETHERNET_TYPE_SENDER.to_be_bytes() {
+                    continue;
+                }
+                match receiver.push_ethernet(frame) {
+                    Ok(Some(decoded)) => {
+                        if frame_tx.send(decoded).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(e) => eprintln!("Invalid packet: {}", e),
+                }
+            }
+        });
+        Ok(frame_rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linsn::{LinsnHeader, LINSN_FRAME_HEIGHT, LINSN_FRAME_WIDTH, PAYLOAD_SIZE_SENDER};
+    use crate::socket::{ChannelSender, LinsnSocket, SenderSettings};
+    use pnet::util::MacAddr;
+
+    fn test_image() -> Vec<Rgb<u8>> {
+        (0..LINSN_FRAME_WIDTH * LINSN_FRAME_HEIGHT)
+            .map(|i| Rgb([i as u8, (i >> 8) as u8, (i >> 16) as u8]))
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip_through_channel() {
+        let (tx, rx) = mpsc::channel();
+        let sender = ChannelSender::new(tx, SenderSettings::default());
+        let image = test_image();
+        sender.send(&image, MacAddr::zero()).unwrap();
+        drop(sender);
+
+        let mut receiver = VirtualReceiver::with_geometry(ColorFormat::BRG, FrameGeometry::default());
+        let frame = receiver.decode_channel(&rx).expect("No frame decoded");
+        assert!(frame.is_complete());
+        assert!(frame.duplicate_chunks.is_empty());
+        assert_eq!(frame.image.pixels().copied().collect::<Vec<_>>(), image);
+    }
+
+    #[test]
+    fn test_round_trip_with_custom_geometry() {
+        let geometry = FrameGeometry::new(256, 100, 0);
+        let (tx, rx) = mpsc::channel();
+        let sender = ChannelSender::new(tx, SenderSettings::default().with_geometry(geometry));
+        let image: Vec<Rgb<u8>> = (0..geometry.pixel_count())
+            .map(|i| Rgb([i as u8, 0, 0xFF]))
+            .collect();
+        sender.send(&image, MacAddr::zero()).unwrap();
+        drop(sender);
+
+        let mut receiver = VirtualReceiver::with_geometry(ColorFormat::BRG, geometry);
+        let frame = receiver.decode_channel(&rx).expect("No frame decoded");
+        assert!(frame.is_complete());
+        assert_eq!(receiver.chunk_count(), 54);
+        assert_eq!(frame.image.pixels().copied().collect::<Vec<_>>(), image);
+    }
+
+    #[test]
+    fn test_reports_missing_and_duplicate_chunks() {
+        let mut receiver = VirtualReceiver::with_geometry(ColorFormat::RGB, FrameGeometry::default());
+        let packet = |package_id| LinsnSenderPacket {
+            header: LinsnHeader::empty(package_id),
+            payload: [0xFF; PAYLOAD_SIZE_SENDER],
+        };
+
+        assert!(receiver.push_packet(&packet(0)).is_none());
+        assert!(receiver.push_packet(&packet(1)).is_none());
+        assert!(receiver.push_packet(&packet(1)).is_none());
+        assert!(receiver.push_packet(&packet(3)).is_none());
+
+        let frame = receiver.push_packet(&packet(0)).expect("Frame not emitted");
+        assert_eq!(frame.duplicate_chunks, vec![1]);
+        assert_eq!(frame.missing_chunks.len(), receiver.chunk_count() - 3);
+        assert_eq!(frame.missing_chunks[0], 2);
+        assert_eq!(frame.image.get_pixel(0, 0), &Rgb([0xFF, 0xFF, 0xFF]));
+        assert_eq!(
+            frame.image.get_pixel(0, LINSN_FRAME_HEIGHT - 1),
+            &Rgb([0, 0, 0])
+        );
+    }
+}